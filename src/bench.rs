@@ -0,0 +1,168 @@
+use std::{cmp::Reverse, path::Path, time::Duration};
+
+use bstr::ByteSlice;
+
+use crate::{
+    bail,
+    cli::{Arguments, BenchOpts, SampleOpts},
+    database::CacheEntry,
+    diff_dir,
+    fs::{self, AlreadyReported},
+    interrupt, load_dir_samples, run_sample, samples, test_command, RunContext, TestStatus,
+};
+
+/// The wall times of the timed runs of a test
+struct Timing {
+    name: String,
+    min: Duration,
+    median: Duration,
+    max: Duration,
+}
+
+/// Runs the binary on every test of its samples files repeatedly and prints a table of the
+/// run times, the slowest tests first
+pub fn subcommand_bench(
+    entry: &CacheEntry,
+    out_dir: &Path,
+    args: &Arguments,
+    sample_options: &SampleOpts,
+    bench_options: &BenchOpts,
+) -> fs::Result<()> {
+    if entry.samples.is_empty() && sample_options.sample_dir.is_none() {
+        bail!(
+            "`{}` has no samples file to bench",
+            fs::display(&entry.source)
+        );
+    }
+
+    let diff_dir = diff_dir(out_dir, sample_options);
+    let context = RunContext {
+        args,
+        options: sample_options,
+        diff_dir: &diff_dir,
+    };
+    let samples = match load_dir_samples(args, sample_options)? {
+        Some((dir, contents)) => vec![(dir.to_owned(), contents)],
+        None => entry
+            .samples
+            .iter()
+            .map(|path| Ok((path.clone(), fs::read(path)?)))
+            .collect::<fs::Result<Vec<_>>>()?,
+    };
+
+    let mut timings = Vec::new();
+    let mut failed = 0;
+    for (samples_out, contents) in &samples {
+        let separator = sample_options.separator.as_deref();
+        let Some(mut sections) = samples::SampleIterator::new(contents, separator) else {
+            bail!("`{}` has no tests", fs::display(samples_out));
+        };
+
+        while let Some(input) = sections.next() {
+            if interrupt::interrupted() {
+                break;
+            }
+            let output = sections.next();
+            let Some(test) = samples::validate_test(samples_out, contents, &input, output.as_ref())
+            else {
+                failed += 1;
+                continue;
+            };
+            let name = samples::qualified_name(&entry.source, samples_out, test.name);
+            if let Some(filter) = &sample_options.filter {
+                if !name.contains(filter.as_str()) {
+                    continue;
+                }
+            }
+            if args.dry_run {
+                fs::print_dry_run(&test_command(&context, &entry.binary, &test.args));
+                continue;
+            }
+
+            match bench_test(&context, entry, bench_options, &name, &test)? {
+                Some(timing) => timings.push(timing),
+                None => failed += 1,
+            }
+        }
+    }
+
+    if !timings.is_empty() {
+        print_timings(&mut timings);
+    }
+    if failed > 0 {
+        bail!("{failed} tests failed and were not timed");
+    }
+    Ok(())
+}
+
+/// Times the runs of a single test, returns `None` if one of them fails
+fn bench_test(
+    context: &RunContext,
+    entry: &CacheEntry,
+    bench_options: &BenchOpts,
+    name: &str,
+    test: &samples::TestCase,
+) -> fs::Result<Option<Timing>> {
+    log::debug!("Benching {name}");
+    let runs = bench_options.runs.get();
+    let mut durations = Vec::with_capacity(runs);
+    // unverified output isn't compared or checked at all, only timed
+    let output = bench_options.verify.then_some(test.output.body);
+    for i in 0..bench_options.warmup + runs {
+        let run = run_sample(
+            context,
+            &entry.binary,
+            name.as_bytes(),
+            &test.input,
+            output,
+            entry,
+            &test.args,
+        )?;
+        // a crash, a timeout or a wrong output with --verify make the timing meaningless
+        if run.status != TestStatus::Passed {
+            log::error!("{name} {}, not timing it", run.status.summary());
+            if !run.stderr.is_empty() {
+                log::info!("stderr:\n{}", run.stderr.to_str_lossy().trim_end());
+            }
+            return Ok(None);
+        }
+        if i >= bench_options.warmup {
+            durations.push(run.duration);
+        }
+    }
+
+    durations.sort();
+    Ok(Some(Timing {
+        name: name.to_owned(),
+        min: durations[0],
+        median: durations[durations.len() / 2],
+        max: durations[durations.len() - 1],
+    }))
+}
+
+fn print_timings(timings: &mut [Timing]) {
+    timings.sort_by_key(|t| Reverse(t.median));
+
+    let total = Timing {
+        name: "total".to_owned(),
+        min: timings.iter().map(|t| t.min).sum(),
+        median: timings.iter().map(|t| t.median).sum(),
+        max: timings.iter().map(|t| t.max).sum(),
+    };
+    let width = timings.iter().map(|t| t.name.len()).max().unwrap().max(5);
+    let seconds = |d: Duration| format!("{:.3}s", d.as_secs_f64());
+
+    println!(
+        "{:<width$} {:>9} {:>9} {:>9}",
+        "test", "min", "median", "max"
+    );
+    for timing in timings.iter().chain([&total]) {
+        println!(
+            "{:<width$} {:>9} {:>9} {:>9}",
+            timing.name,
+            seconds(timing.min),
+            seconds(timing.median),
+            seconds(timing.max)
+        );
+    }
+}
@@ -1,5 +1,10 @@
 use clap::{Args, ColorChoice, Parser, Subcommand, ValueEnum};
-use std::{ffi::OsString, path::PathBuf};
+use std::{
+    ffi::OsString,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Copy, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum Interactivity {
@@ -19,28 +24,476 @@ pub enum Os {
     Windows,
 }
 
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, ValueEnum)]
+pub enum OptLevel {
+    #[value(name = "0")]
+    O0,
+    #[value(name = "1")]
+    O1,
+    #[value(name = "2")]
+    O2,
+    #[value(name = "3")]
+    O3,
+    /// Optimize for size
+    #[value(name = "s")]
+    Os,
+}
+
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Sanitizer {
+    /// AddressSanitizer, also enables debug info for readable stack traces
+    Address,
+    /// UndefinedBehaviorSanitizer
+    Undefined,
+    /// ThreadSanitizer
+    Thread,
+}
+
 #[derive(Debug, Args)]
 pub struct BuildOpts {
     /// Define a preprocessor variable
-    #[arg(long = "define", short = 'D', value_name = "<macroname>=<value>", action = clap::ArgAction::Append)]
+    ///
+    /// The define is passed to the compiler as a single argument, so the value can contain spaces
+    /// and quotes like `--define 'NAME="hello world"'`
+    #[arg(long = "define", short = 'D', value_name = "<macroname>=<value>", action = clap::ArgAction::Append, value_parser = parse_define)]
     pub defines: Vec<String>,
-    /// Additional options to pass to the compiler, split by whitespace
+    /// Add a directory to the include search path with -I, can be specified multiple times
+    #[arg(long = "include-dir", value_name = "DIR", action = clap::ArgAction::Append)]
+    pub include_dirs: Vec<PathBuf>,
+    /// Link with a library with -l, can be specified multiple times
+    #[arg(long = "link", value_name = "LIB", action = clap::ArgAction::Append)]
+    pub libraries: Vec<String>,
+    /// Additional options to pass to the compiler, split like the shell does so that quoted options can contain spaces
     #[arg(long = "options", value_name = "STRING")]
     pub compiler_args: Option<String>,
-    /// This disables the default progtest arguments '-std=c++11 -Wall -pedantic'
+    /// The C++ compiler to invoke, split by whitespace so that a wrapper like 'ccache g++' can be used [default: g++]
+    #[arg(long, alias = "compiler", value_name = "PROGRAM")]
+    pub cxx: Option<String>,
+    /// The compiler to invoke for C sources, split by whitespace like --cxx [default: gcc]
+    #[arg(long, value_name = "PROGRAM")]
+    pub cc: Option<String>,
+    /// The optimization level passed to the compiler as -O<LEVEL>
+    #[arg(long, value_name = "LEVEL")]
+    pub opt_level: Option<OptLevel>,
+    /// Instrument the binaries with a sanitizer, can be specified multiple times
+    #[arg(long, value_name = "SANITIZER", action = clap::ArgAction::Append)]
+    pub sanitize: Vec<Sanitizer>,
+    /// This disables the default progtest arguments '-std=c++11 -Wall -pedantic' ('-std=c11' for C)
     #[arg(long, value_name = "STRING")]
     pub no_default_args: bool,
+    /// Pass -Werror to the compiler so that warnings fail the build
+    ///
+    /// It is kept with --no-default-args, the warnings are then the ones enabled by --options
+    #[arg(long)]
+    pub werror: bool,
+    /// Append the whitespace-separated options in `<source>.flags` next to a source when building it
+    #[arg(long)]
+    pub per_file_flags: bool,
+    /// Include the version reported by the compiler in the build hash, rebuilding everything when it changes
+    #[arg(long)]
+    pub compiler_version_guard: bool,
+    /// Kill the compiler if compiling or linking a target takes longer than this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub compiler_timeout: Option<Duration>,
+    /// Retry running the compiler up to N times when it can't be started or its output can't
+    /// be read, compilation errors are never retried
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub compile_retries: u32,
+    /// Log every compiler command and how long it took, without the rest of the -vv output
+    #[arg(long)]
+    pub verbose_compiler: bool,
+    /// A directory shared between roots where built binaries are stored by the hash of their
+    /// sources and options, a target is copied from it instead of compiled if it is found
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Stop building after the first target fails to build
+    #[arg(long)]
+    pub fail_fast: bool,
     /// The names of the source files to use, relative to the root directory
     #[arg(value_name = "DIR")]
     pub targets: Vec<PathBuf>,
 }
 
+impl BuildOpts {
+    /// Returns the compiler command for the source file depending on its language
+    pub fn compiler(&self, source: &Path) -> &str {
+        match is_c_source(source) {
+            true => self.cc.as_deref().unwrap_or("gcc"),
+            false => self.cxx.as_deref().unwrap_or("g++"),
+        }
+    }
+    /// Returns the compiler which links the translation units, C++ if any of them is C++
+    pub fn linker<'a>(&self, mut sources: impl Iterator<Item = &'a Path>) -> &str {
+        match sources.all(is_c_source) {
+            true => self.cc.as_deref().unwrap_or("gcc"),
+            false => self.cxx.as_deref().unwrap_or("g++"),
+        }
+    }
+    /// Returns the compiler arguments for --opt-level and --sanitize
+    pub fn profile_args(&self) -> Vec<&'static str> {
+        let mut args = Vec::new();
+        if let Some(level) = self.opt_level {
+            args.push(match level {
+                OptLevel::O0 => "-O0",
+                OptLevel::O1 => "-O1",
+                OptLevel::O2 => "-O2",
+                OptLevel::O3 => "-O3",
+                OptLevel::Os => "-Os",
+            });
+        }
+        for sanitizer in &self.sanitize {
+            args.push(match sanitizer {
+                Sanitizer::Address => "-fsanitize=address",
+                Sanitizer::Undefined => "-fsanitize=undefined",
+                Sanitizer::Thread => "-fsanitize=thread",
+            });
+        }
+        if self.sanitize.contains(&Sanitizer::Address) {
+            args.push("-g");
+        }
+        args
+    }
+    pub fn default_args(&self, source: &Path) -> &'static [&'static str] {
+        match is_c_source(source) {
+            true => &["-std=c11", "-Wall", "-pedantic"],
+            false => &["-std=c++11", "-Wall", "-pedantic"],
+        }
+    }
+}
+
+fn is_c_source(source: &Path) -> bool {
+    source.extension().is_some_and(|e| e == "c")
+}
+
+#[derive(Debug, Args)]
+pub struct EnvOpts {
+    /// Set an environment variable for the binary, can be specified multiple times
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub vars: Vec<(String, String)>,
+    /// Don't inherit the environment, only the variables from --env are set
+    #[arg(long)]
+    pub clear_env: bool,
+}
+
+impl EnvOpts {
+    /// Applies the environment options to the command for a binary
+    pub fn apply(&self, command: &mut std::process::Command) {
+        if self.clear_env {
+            command.env_clear();
+        }
+        command.envs(self.vars.iter().map(|(k, v)| (k, v)));
+    }
+}
+
+fn parse_env(str: &str) -> Result<(String, String), String> {
+    match str.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        Some(_) => Err("the variable name must not be empty".to_owned()),
+        None => Err("expected KEY=VALUE".to_owned()),
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct TestOpts {
+    #[clap(flatten)]
+    pub sample_options: SampleOpts,
+    /// The command to run to diff mismatched outputs, a built-in unified diff is printed if omitted
+    ///
+    /// It is interpreted by the shell, the variables $INPUT, $EXPECTED, and $ACTUAL are present
+    #[arg(long, value_name = "STRING")]
+    pub diff: Option<String>,
+    /// Run the --diff command once after all tests instead of after every failed test
+    ///
+    /// The variables $INPUT, $EXPECTED, and $ACTUAL then list the files of all failed tests
+    /// separated by newlines, in the same order
+    #[arg(long, requires = "diff")]
+    pub diff_batch: bool,
+    /// Show the built-in diff as two side-by-side columns when both outputs have at most MAX_LINES lines [default: 40]
+    ///
+    /// Changed lines are marked with `|`, lines only in the expected or actual output with `<` or `>`,
+    /// longer outputs fall back to the unified diff
+    #[arg(long, value_name = "MAX_LINES", num_args = 0..=1, require_equals = true, default_missing_value = "40")]
+    pub verbose_diff: Option<usize>,
+    /// Only run the tests which didn't pass the last time they ran, the results are saved in `out`
+    #[arg(long)]
+    pub failed: bool,
+    /// Run every test N times, tests whose runs don't agree are reported as flaky
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub repeat: NonZeroUsize,
+    /// Run the tests of every samples file in a random order to expose dependencies between them
+    ///
+    /// The seed is logged so that the order can be reproduced with --seed
+    #[arg(long)]
+    pub shuffle: bool,
+    /// The seed of the --shuffle order, a random one is picked otherwise
+    #[arg(long, value_name = "N", requires = "shuffle")]
+    pub seed: Option<u64>,
+    /// Log passing tests only with --verbose, so that failures stand out
+    #[arg(long)]
+    pub quiet_passing: bool,
+    /// After testing, print the N slowest tests
+    #[arg(long, value_name = "N")]
+    pub slowest: Option<usize>,
+    /// Write the test results to a JSON file
+    ///
+    /// The file contains an array of objects with the fields `source`, `name`, `duration_ms`
+    /// and `status`, which is one of `ok`, `fail`, `timeout`, `crash`, `memory_limit`,
+    /// `output_limit` or `flaky`
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+    /// On an output mismatch, offer to replace the expected output in the samples file with the actual one
+    ///
+    /// With `--ask no` all mismatched outputs are replaced without prompting, with `--ask skip` none are
+    #[arg(long, conflicts_with_all = ["baseline", "sample_dir"])]
+    pub update: bool,
+    /// A reference binary run on the same inputs, its output is expected instead of the stored one
+    #[arg(long, value_name = "PATH")]
+    pub baseline: Option<PathBuf>,
+    /// Write the test results to a JUnit XML file, with a testsuite for every source file
+    #[arg(long, value_name = "PATH")]
+    pub junit: Option<PathBuf>,
+    /// A label for this run, printed before the summary and included in the --report and --junit files
+    #[arg(long, value_name = "STRING")]
+    pub tag: Option<String>,
+    /// Test every C/C++ source under the root which has a samples file next to it
+    ///
+    /// Hidden directories, the output directory, `target` and `node_modules` are skipped
+    #[arg(long, conflicts_with = "targets")]
+    pub discover: bool,
+    /// After testing, keep rebuilding and retesting whenever the sources, their headers or samples change
+    #[arg(long)]
+    pub watch: bool,
+    /// Print the names of the tests in the samples files without building or running anything
+    ///
+    /// Tests with a malformed header or without an output section are marked as invalid
+    #[arg(long, conflicts_with = "watch")]
+    pub list: bool,
+}
+
+// how the tested binary is run and its output checked, shared by `test` and `bench`
+#[derive(Debug, Args)]
+pub struct SampleOpts {
+    #[clap(flatten)]
+    pub env: EnvOpts,
+    /// The command which decides whether an output is correct, for problems with multiple correct answers
+    ///
+    /// It is interpreted by the shell with the same variables as --diff, the output is accepted
+    /// if it exits with zero, otherwise the test fails
+    #[arg(long, value_name = "STRING")]
+    pub checker: Option<String>,
+    /// Kill the tested binary if a single test runs longer than this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub timeout: Option<Duration>,
+    /// Only run tests whose name contains this substring, the match is case-sensitive
+    #[arg(long, value_name = "SUBSTRING")]
+    pub filter: Option<String>,
+    /// Ignore spaces and tabs at the end of lines and a missing or extra trailing newline when comparing outputs
+    #[arg(long)]
+    pub ignore_trailing_ws: bool,
+    /// Treat CRLF line endings as LF in both outputs when comparing them, the diff files keep the raw bytes
+    #[arg(long)]
+    pub normalize_newlines: bool,
+    /// Compare outputs as whitespace-separated tokens, allowing numbers to differ by EPS
+    ///
+    /// Tokens which both parse as floating point numbers match if their absolute difference
+    /// or their difference relative to the larger magnitude is at most EPS, all other tokens
+    /// must match exactly and both outputs must have the same number of tokens
+    #[arg(long, value_name = "EPS", value_parser = parse_tolerance)]
+    pub float_tolerance: Option<f64>,
+    /// Only compare these lines of the outputs, numbered from 1 as `N`, `FIRST-LAST` or `FIRST-`
+    ///
+    /// The diff files still contain the full outputs
+    #[arg(long, value_name = "RANGE", value_parser = parse_line_range)]
+    pub compare_lines: Option<LineRange>,
+    /// Only compare the first capture group, or the whole match, of this regex on every line
+    ///
+    /// Lines which don't match are ignored. With --compare-lines the regex is only matched against
+    /// the selected lines. The diff files still contain the full outputs
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    pub extract: Option<regex::bytes::Regex>,
+    /// Don't treat a nonzero exit code or termination by a signal as a failure
+    #[arg(long)]
+    pub ignore_exit_status: bool,
+    /// Limit the address space of tested binaries to this many megabytes (unix only)
+    ///
+    /// This is a best-effort limit, allocations over it fail. Only an abort which reports the
+    /// failed allocation, like an uncaught `std::bad_alloc`, is reported as exceeding the limit,
+    /// other crashes are only noted as possibly caused by it
+    #[arg(long, value_name = "MB")]
+    pub memory_limit: Option<u64>,
+    /// Kill the tested binary once it prints more than this many bytes to stdout or stderr
+    #[arg(long, value_name = "N", default_value_t = 64 * 1024 * 1024)]
+    pub max_output_bytes: u64,
+    /// Discard the stderr of the tested binary instead of saving it next to the diff files
+    ///
+    /// The discarded output doesn't count towards --max-output-bytes
+    #[arg(long)]
+    pub quiet_child: bool,
+    /// Test against the `*_in.txt` and `*_out.txt` files in this directory instead of the samples files
+    ///
+    /// The files are paired up like `convert` does with its default options, following --os
+    #[arg(long, value_name = "DIR")]
+    pub sample_dir: Option<PathBuf>,
+    /// The directory for the input, expected and actual output of failed tests [default: out/diff]
+    #[arg(long, value_name = "DIR")]
+    pub diff_dir: Option<PathBuf>,
+    /// The line separating sections of the samples files, inferred from their first line if omitted
+    ///
+    /// A line of the input or output which is exactly the separator is mistaken for the start of
+    /// a new section, in that case convert the archive with a separator which doesn't collide
+    #[arg(long, value_name = "STRING", value_parser = parse_separator)]
+    pub separator: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchOpts {
+    /// How many timed runs of every test, the table shows their minimum, median and maximum
+    #[arg(long, value_name = "N", default_value = "10")]
+    pub runs: NonZeroUsize,
+    /// Run every test N more times before the timed runs and discard their timings
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub warmup: usize,
+    /// Fail tests whose output doesn't match the expected one, otherwise the output is ignored
+    #[arg(long)]
+    pub verify: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ConvertOpts {
+    pub archive: PathBuf,
+    pub output: Option<PathBuf>,
+    /// Name of a top-level directory within the samples archive to include [default: CZE]
+    ///
+    /// The default only applies when neither --subdir nor --exclude is given, so that --exclude
+    /// alone includes all other directories
+    #[arg(long = "subdir", value_name = "STRING")]
+    pub sample_subdirs: Vec<OsString>,
+    /// Name of a top-level directory within the samples archive to skip, takes precedence over --subdir
+    #[arg(long, value_name = "STRING")]
+    pub exclude: Vec<OsString>,
+    /// Follow symlinks to directories within the archive, symlinks to files are always followed
+    #[arg(long)]
+    pub follow_symlinks: bool,
+    /// Fail instead of skipping samples which are missing their input or output file
+    #[arg(long)]
+    pub strict: bool,
+    /// Fail if the archive contains no samples, for example because --subdir matched nothing
+    #[arg(long)]
+    pub require_samples: bool,
+    /// The line to write between sections of the samples file
+    ///
+    /// A line of the input or output which is exactly the separator would be mistaken for the
+    /// start of a new section when testing, such samples are reported with a warning
+    #[arg(long, value_name = "STRING", default_value = "---", value_parser = parse_separator)]
+    pub separator: String,
+    /// Store inputs larger than BYTES gzip compressed and base64 encoded [default: 65536]
+    ///
+    /// Their sections are marked with `<name> in gz` and decompressed when testing
+    #[arg(long, value_name = "BYTES", num_args = 0..=1, require_equals = true, default_missing_value = "65536")]
+    pub compress_large: Option<usize>,
+    /// Write a JSON list of the included samples with their input and output files in the archive
+    #[arg(long, value_name = "PATH")]
+    pub manifest: Option<PathBuf>,
+    /// Leave the extracted archive in `out/extract` for inspection
+    #[arg(long)]
+    pub keep_extracted: bool,
+    /// Remove `out/extract` after a successful conversion, this is the default
+    #[arg(long, conflicts_with = "keep_extracted")]
+    pub clean_extracted: bool,
+}
+
+fn parse_seconds(str: &str) -> Result<Duration, String> {
+    let seconds = str.parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+fn parse_separator(str: &str) -> Result<String, String> {
+    if str.trim().is_empty() {
+        Err("the separator must not be empty".to_owned())
+    } else if str.contains(['\n', '\r']) {
+        Err("the separator must be a single line".to_owned())
+    } else {
+        Ok(str.to_owned())
+    }
+}
+
+/// Rejects defines without a name and multi-line defines, which would corrupt the command
+pub fn parse_define(str: &str) -> Result<String, String> {
+    if str.is_empty() || str.starts_with('=') {
+        Err("the define must start with a macro name".to_owned())
+    } else if str.contains(['\n', '\r']) {
+        Err("the define must be a single line".to_owned())
+    } else {
+        Ok(str.to_owned())
+    }
+}
+
+/// An inclusive range of lines numbered from 1, the last line is unbounded if omitted
+#[derive(Debug, Clone, Copy)]
+pub struct LineRange {
+    pub first: usize,
+    pub last: Option<usize>,
+}
+
+impl LineRange {
+    pub fn contains(&self, line: usize) -> bool {
+        line >= self.first && self.last.is_none_or(|last| line <= last)
+    }
+}
+
+fn parse_line_range(str: &str) -> Result<LineRange, String> {
+    let parse = |s: &str| match s.trim().parse::<usize>() {
+        Ok(0) => Err("lines are numbered from 1".to_owned()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("invalid line number `{s}`: {e}")),
+    };
+    let range = match str.split_once('-') {
+        Some((first, "")) => LineRange {
+            first: parse(first)?,
+            last: None,
+        },
+        Some((first, last)) => LineRange {
+            first: parse(first)?,
+            last: Some(parse(last)?),
+        },
+        None => {
+            let line = parse(str)?;
+            LineRange {
+                first: line,
+                last: Some(line),
+            }
+        }
+    };
+    match range.last {
+        Some(last) if last < range.first => Err("the range ends before it starts".to_owned()),
+        _ => Ok(range),
+    }
+}
+
+fn parse_regex(str: &str) -> Result<regex::bytes::Regex, String> {
+    regex::bytes::Regex::new(str).map_err(|e| e.to_string())
+}
+
+fn parse_tolerance(str: &str) -> Result<f64, String> {
+    match str.parse::<f64>() {
+        Ok(eps) if eps >= 0.0 => Ok(eps),
+        Ok(_) => Err("the tolerance must be a non-negative number".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// parsed once at startup, the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// Build multiple binaries
     Build {
         #[clap(flatten)]
         build_options: BuildOpts,
+        /// Print the path of every built binary on its own line to stdout, other output except
+        /// errors is hidden unless --verbose is given
+        #[arg(long)]
+        print_binaries: bool,
     },
     /// Build binaries, then run specified command with them
     With {
@@ -49,54 +502,116 @@ pub enum Command {
         /// Command and arguments to execute as the `with` command, the placeholder {bin} denotes the path to the built binaries, it is appended to the arguments if omitted
         #[arg(last = true)]
         with: Vec<OsString>,
+        /// Run the command once for every binary instead of once with all of them
+        #[arg(long)]
+        each: bool,
     },
     /// Run a single binary which inherits stdin
     Run {
         #[clap(flatten)]
         build_options: BuildOpts,
+        #[clap(flatten)]
+        env: EnvOpts,
+        /// Read the stdin of the binary from this file, relative to the root
+        #[arg(long, value_name = "FILE")]
+        stdin: Option<PathBuf>,
+        /// Arguments to pass to the binary
+        #[arg(last = true)]
+        args: Vec<OsString>,
     },
     /// Run multiple binaries, then feed them test files extracted from their neighboring samples archive
     Test {
         #[clap(flatten)]
         build_options: BuildOpts,
-        /// The command to run to diff mismatched outputs
-        ///
-        /// It is interpreted by the shell, the variables $INPUT, $EXPECTED, and $ACTUAL are present
-        /// Command and arguments to execute as the `with` command, the placeholder {bin} denotes the
-        /// path to the built binaries, it is appended to the arguments if omitted
-        #[arg(long, value_name = "STRING")]
-        diff: Option<String>,
+        #[clap(flatten)]
+        test_options: TestOpts,
+    },
+    /// Build a single binary and time it on the tests of its samples file
+    Bench {
+        #[clap(flatten)]
+        build_options: BuildOpts,
+        #[clap(flatten)]
+        sample_options: SampleOpts,
+        #[clap(flatten)]
+        bench_options: BenchOpts,
     },
-    /// Convert a sample .tar.gz archive to a .sample file
+    /// Convert a sample archive (.tar.gz, .tar.xz, .tar.bz2 or .zip) to a .sample file
     Convert {
-        archive: PathBuf,
-        output: Option<PathBuf>,
-        /// Name of a subdirectory within the samples archive to include
-        #[arg(long = "subdir", value_name = "STRING", default_values_os_t = [OsString::from("CZE")])]
-        sample_subdirs: Vec<OsString>,
+        #[clap(flatten)]
+        convert_options: ConvertOpts,
+    },
+    /// Check that a samples file is well-formed without building or running anything
+    Check {
+        /// The samples file to check
+        file: PathBuf,
+        /// The line separating sections of the samples file, inferred from its first line if omitted
+        #[arg(long, value_name = "STRING", value_parser = parse_separator)]
+        separator: Option<String>,
+    },
+    /// Create an example source file with samples to get started
+    Init {
+        /// The directory to create the files in, relative to the root
+        dir: Option<PathBuf>,
+        /// Overwrite the files if they already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Delete the output directory, or only the artifacts of the given targets
+    Clean {
+        /// The targets whose binary, cache entry and test outputs to remove
+        targets: Vec<PathBuf>,
     },
-    /// Delete the output directory
-    Clean,
 }
 
 impl Command {
     pub fn get_build_options(&self) -> Option<&BuildOpts> {
         match self {
-            Command::Build { build_options }
+            Command::Build { build_options, .. }
             | Command::With { build_options, .. }
-            | Command::Run { build_options }
-            | Command::Test { build_options, .. } => Some(build_options),
-            Command::Clean | Command::Convert { .. } => None,
+            | Command::Run { build_options, .. }
+            | Command::Test { build_options, .. }
+            | Command::Bench { build_options, .. } => Some(build_options),
+            Command::Clean { .. }
+            | Command::Convert { .. }
+            | Command::Check { .. }
+            | Command::Init { .. } => None,
         }
     }
+    pub fn get_build_options_mut(&mut self) -> Option<&mut BuildOpts> {
+        match self {
+            Command::Build { build_options, .. }
+            | Command::With { build_options, .. }
+            | Command::Run { build_options, .. }
+            | Command::Test { build_options, .. }
+            | Command::Bench { build_options, .. } => Some(build_options),
+            Command::Clean { .. }
+            | Command::Convert { .. }
+            | Command::Check { .. }
+            | Command::Init { .. } => None,
+        }
+    }
+}
+
+fn default_jobs() -> NonZeroUsize {
+    std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
 }
 
 #[derive(Debug, Parser)]
-#[command(name = "testprog", about = "A program tester to run progtest locally", long_about = None)]
+#[command(
+    name = "testprog",
+    about = "A program tester to run progtest locally",
+    long_about = None,
+    after_help = "Ctrl-C stops after the running builds and tests finish and saves the cache, a second Ctrl-C exits immediately"
+)]
 pub struct Arguments {
     /// Sets the root path of the project, otherwise PWD is used
     #[arg(long, value_name = "DIR", default_value_os_t = std::env::current_dir().unwrap())]
     pub root: PathBuf,
+    /// The directory for the binaries, the cache and test outputs, relative to the root [default: out]
+    ///
+    /// `clean` without targets deletes this whole directory
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
     /// Increase output verbosity, can be specified second time to get trace messages
     #[arg(
         long,
@@ -110,16 +625,69 @@ pub struct Arguments {
     /// Select os' sample file variant to use (line endings)
     #[arg(long, value_enum, default_value_t = Os::Unix)]
     pub os: Os,
-    /// Whether to prompt the user
+    /// Whether to prompt the user, prompting is skipped when stdin isn't a terminal
     #[arg(long, value_enum, default_value_t = Interactivity::Yes)]
     pub ask: Interactivity,
-    /// The action to perform
+    /// Show diffs of failed tests without prompting, the same as `--ask no`
+    #[arg(long, conflicts_with = "ask")]
+    pub no_prompt: bool,
+    /// Whether to color the output of testprog itself
     #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
     pub color: ColorChoice,
+    /// Whether programs started by `run`, `with` and `--diff` should color their output
+    ///
+    /// `always` sets `CLICOLOR_FORCE=1` and `never` sets `NO_COLOR=1` in their environment,
+    /// with `auto` they inherit it and decide on their own
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub child_color: ColorChoice,
     /// Do not read or save the cache.json file
     #[arg(long = "no-cache", default_value_t = false)]
     pub no_cache: bool,
+    /// Print the compiler and test commands instead of running them, nothing is marked as built
+    #[arg(long)]
+    pub dry_run: bool,
+    /// After building, print how many targets were rebuilt and how many were up to date
+    #[arg(long)]
+    pub stats: bool,
+    /// Prefix log messages with the time elapsed since the start
+    #[arg(long)]
+    pub timestamps: bool,
+    /// The number of compiler processes or tested binaries to run in parallel, defaults to the number of logical CPUs
+    ///
+    /// Binaries are tested one at a time when prompting with `--ask yes`
+    #[arg(long, short, value_name = "N", default_value_t = default_jobs())]
+    pub jobs: NonZeroUsize,
 
     #[clap(subcommand)]
     pub command: Command,
 }
+
+impl Arguments {
+    /// Sets the color variables of a child process according to `--child-color`
+    pub fn apply_child_color(&self, command: &mut std::process::Command) {
+        match self.child_color {
+            ColorChoice::Auto => {}
+            ColorChoice::Always => {
+                command.env("CLICOLOR_FORCE", "1").env_remove("NO_COLOR");
+            }
+            ColorChoice::Never => {
+                command.env("NO_COLOR", "1").env_remove("CLICOLOR_FORCE");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defines_must_be_named_and_single_line() {
+        assert!(parse_define("").is_err());
+        assert!(parse_define("=1").is_err());
+        assert!(parse_define("A=1\nB").is_err());
+        assert!(parse_define("A=1\r").is_err());
+        assert_eq!(parse_define("A").unwrap(), "A");
+        assert_eq!(parse_define(r#"A="b c""#).unwrap(), r#"A="b c""#);
+    }
+}
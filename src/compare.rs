@@ -0,0 +1,141 @@
+use std::borrow::Cow;
+
+use bstr::ByteSlice;
+
+use crate::cli::SampleOpts;
+
+/// Compares the expected and actual output of a test according to the options
+pub fn outputs_match(expected: &[u8], actual: &[u8], options: &SampleOpts) -> bool {
+    let (expected, actual) = (select(expected, options), select(actual, options));
+    let (expected, actual) = (normalize(&expected, options), normalize(&actual, options));
+    match options.float_tolerance {
+        Some(eps) => tokens_match(&expected, &actual, eps),
+        None => expected == actual,
+    }
+}
+
+/// Reduces an output to the lines selected by --compare-lines and then to the parts
+/// extracted from them by --extract
+pub fn select<'a>(bytes: &'a [u8], options: &SampleOpts) -> Cow<'a, [u8]> {
+    if options.compare_lines.is_none() && options.extract.is_none() {
+        return Cow::Borrowed(bytes);
+    }
+
+    let mut selected = Vec::new();
+    for (index, line) in bytes.lines_with_terminator().enumerate() {
+        if let Some(range) = &options.compare_lines {
+            if !range.contains(index + 1) {
+                continue;
+            }
+        }
+        let Some(regex) = &options.extract else {
+            selected.extend_from_slice(line);
+            continue;
+        };
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        if let Some(captures) = regex.captures(content) {
+            let part = captures.get(1).or(captures.get(0)).unwrap();
+            selected.extend_from_slice(part.as_bytes());
+            selected.push(b'\n');
+        }
+    }
+    Cow::Owned(selected)
+}
+
+fn normalize<'a>(bytes: &'a [u8], options: &SampleOpts) -> Cow<'a, [u8]> {
+    let mut bytes = Cow::Borrowed(bytes);
+    if options.normalize_newlines && bytes.contains(&b'\r') {
+        bytes = Cow::Owned(bytes.replace(b"\r\n", b"\n"));
+    }
+    if options.ignore_trailing_ws {
+        bytes = Cow::Owned(strip_trailing_whitespace(&bytes));
+    }
+    bytes
+}
+
+/// Describes the first line on which the outputs differ
+pub fn first_difference(expected: &[u8], actual: &[u8]) -> String {
+    let (mut expected_lines, mut actual_lines) = (expected.lines(), actual.lines());
+    let mut line = 1;
+    loop {
+        return match (expected_lines.next(), actual_lines.next()) {
+            (Some(a), Some(b)) if a == b => {
+                line += 1;
+                continue;
+            }
+            (Some(a), Some(b)) => format!(
+                "first difference at line {line}: expected `{}`, got `{}`",
+                shorten(a),
+                shorten(b)
+            ),
+            // the binary likely crashed or was cut off without a nonzero exit code
+            (Some(a), None) => format!(
+                "the output ends early at line {line}, expected `{}`",
+                shorten(a)
+            ),
+            (None, Some(b)) => format!("extra output from line {line}: `{}`", shorten(b)),
+            (None, None) if expected.ends_with(b"\n") != actual.ends_with(b"\n") => {
+                "the outputs differ in the trailing newline".to_owned()
+            }
+            (None, None) => "the outputs differ in line endings".to_owned(),
+        };
+    }
+}
+
+fn shorten(line: &[u8]) -> String {
+    const MAX_CHARS: usize = 60;
+    let line = line.to_str_lossy();
+    match line.char_indices().nth(MAX_CHARS) {
+        Some((index, _)) => format!("{}...", &line[..index]),
+        None => line.into_owned(),
+    }
+}
+
+/// Compares the outputs token by token, numeric tokens may differ by `eps`
+fn tokens_match(expected: &[u8], actual: &[u8], eps: f64) -> bool {
+    let (mut expected, mut actual) = (tokens(expected), tokens(actual));
+    loop {
+        match (expected.next(), actual.next()) {
+            (None, None) => return true,
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) => match (parse_float(a), parse_float(b)) {
+                (Some(a), Some(b)) if floats_match(a, b, eps) => {}
+                _ => return false,
+            },
+            // differing token count
+            _ => return false,
+        }
+    }
+}
+
+fn tokens(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes
+        .split(|c| c.is_ascii_whitespace())
+        .filter(|token| !token.is_empty())
+}
+
+fn parse_float(token: &[u8]) -> Option<f64> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+fn floats_match(a: f64, b: f64, eps: f64) -> bool {
+    let difference = (a - b).abs();
+    difference <= eps || difference <= eps * a.abs().max(b.abs())
+}
+
+/// Strips spaces and tabs from the end of each line along with any trailing newlines
+fn strip_trailing_whitespace(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    for line in bytes.split(|&c| c == b'\n') {
+        let end = line
+            .iter()
+            .rposition(|&c| c != b' ' && c != b'\t')
+            .map_or(0, |i| i + 1);
+        out.extend_from_slice(&line[..end]);
+        out.push(b'\n');
+    }
+    while out.last() == Some(&b'\n') {
+        out.pop();
+    }
+    out
+}
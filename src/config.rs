@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    bail,
+    cli::{parse_define, BuildOpts},
+    fs::{self, AlreadyReported},
+};
+
+pub const CONFIG_FILE: &str = "testprog.toml";
+
+/// Default build options read from `testprog.toml` in the root directory
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    defines: Option<Vec<String>>,
+    compiler_args: Option<String>,
+    no_default_args: Option<bool>,
+    #[serde(alias = "compiler")]
+    cxx: Option<String>,
+    cc: Option<String>,
+    targets: Option<Vec<PathBuf>>,
+}
+
+impl Config {
+    /// Reads the config file from the root directory, returns `None` if there is none
+    pub fn load(root: &Path) -> fs::Result<Option<Config>> {
+        let path = root.join(CONFIG_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let config = toml::from_str::<Config>(&contents).map_err(|e| {
+            let message = format!("failed to parse config `{}`", fs::display(&path));
+            fs::report_custom(message, e)
+        })?;
+
+        for define in config.defines.iter().flatten() {
+            if let Err(e) = parse_define(define) {
+                bail!(
+                    "invalid define `{define}` in config `{}`: {e}",
+                    fs::display(&path)
+                );
+            }
+        }
+
+        log::debug!("loaded config `{}`", fs::display(&path));
+        Ok(Some(config))
+    }
+    /// Fills in the options which weren't specified on the command line
+    pub fn apply(self, options: &mut BuildOpts) {
+        if let Some(defines) = self.defines.filter(|_| options.defines.is_empty()) {
+            log::debug!("config: defines {defines:?}");
+            options.defines = defines;
+        }
+        if let Some(compiler_args) = self
+            .compiler_args
+            .filter(|_| options.compiler_args.is_none())
+        {
+            log::debug!("config: compiler_args {compiler_args:?}");
+            options.compiler_args = Some(compiler_args);
+        }
+        if let Some(no_default_args) = self.no_default_args.filter(|_| !options.no_default_args) {
+            log::debug!("config: no_default_args {no_default_args}");
+            options.no_default_args = no_default_args;
+        }
+        if let Some(cxx) = self.cxx.filter(|_| options.cxx.is_none()) {
+            log::debug!("config: cxx {cxx:?}");
+            options.cxx = Some(cxx);
+        }
+        if let Some(cc) = self.cc.filter(|_| options.cc.is_none()) {
+            log::debug!("config: cc {cc:?}");
+            options.cc = Some(cc);
+        }
+        if let Some(targets) = self.targets.filter(|_| options.targets.is_empty()) {
+            log::debug!("config: targets {targets:?}");
+            options.targets = targets;
+        }
+    }
+}
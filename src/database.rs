@@ -1,13 +1,25 @@
 use crate::cli::BuildOpts;
 use crate::fs::{self, report, report_io_error, AlreadyReported};
-use crate::{check_status, print_args};
+use crate::progress::{self, Progress};
+use crate::{bail, interrupt, print_args, samples};
+use bstr::ByteSlice;
+use serde::{Deserialize, Serialize};
 use std::{
-    cell::Cell,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
     hash::{Hash, Hasher},
-    os::unix::prelude::OsStrExt,
-    path::{Path, PathBuf},
+    io::Write,
+    num::NonZeroUsize,
+    os::unix::prelude::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+    process::Stdio,
     rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 #[derive(Clone)]
@@ -17,42 +29,207 @@ pub struct CacheEntry {
     pub source: PathBuf,
     // output binary
     pub binary: PathBuf,
-    // uncompressed samples
-    pub samples_out: Option<PathBuf>,
+    // compiler output of the last build
+    pub build_log: PathBuf,
+    // uncompressed samples, `<source>.samples` and then any `<source stem>.<group>.samples`
+    pub samples: Vec<PathBuf>,
+    // files included by the source during the last successful build
+    dependencies: RefCell<Vec<PathBuf>>,
+    // compiler version of the last successful build with --compiler-version-guard
+    toolchain: RefCell<Option<String>>,
+    // object files of the translation units are kept in a directory for every target
+    objects_dir: PathBuf,
+    objects: RefCell<Vec<ObjectFile>>,
+}
+
+/// A translation unit compiled on its own, so that it is only recompiled when it or
+/// the headers it includes change
+#[derive(Clone, Debug)]
+struct ObjectFile {
+    source: PathBuf,
+    // hash of the source, its dependencies and the command compiling it
+    hash: u128,
+    dependencies: Vec<PathBuf>,
 }
 
 impl CacheEntry {
-    pub fn from_serialized(source_file: &Path, source_hash: u128, out_dir: &Path) -> CacheEntry {
-        let samples_out = source_file.with_extension("samples");
+    pub fn from_serialized(
+        source_file: &Path,
+        source_hash: u128,
+        dependencies: Vec<PathBuf>,
+        toolchain: Option<String>,
+        out_dir: &Path,
+    ) -> CacheEntry {
         Self {
             source_hash: Cell::new(source_hash),
             source: source_file.to_owned(),
-            binary: out_dir.join(&source_file).with_extension(""),
-            samples_out: samples_out.is_file().then_some(samples_out),
+            binary: out_dir.join(source_file).with_extension(""),
+            build_log: {
+                let mut log = out_dir.join("build").join(source_file).into_os_string();
+                log.push(".log");
+                PathBuf::from(log)
+            },
+            samples: samples::samples_files(source_file),
+            dependencies: RefCell::new(dependencies),
+            toolchain: RefCell::new(toolchain),
+            objects_dir: out_dir.join("obj").join(source_file),
+            objects: RefCell::new(Vec::new()),
         }
     }
+    /// The object file of a translation unit, `..` in its path is replaced so that it stays
+    /// inside the objects directory
+    fn object_path(&self, source: &Path) -> PathBuf {
+        let mut object = self.objects_dir.clone().into_os_string();
+        for component in source.components() {
+            match component {
+                Component::Normal(name) => {
+                    object.push("/");
+                    object.push(name);
+                }
+                Component::ParentDir => object.push("/_up"),
+                _ => {}
+            }
+        }
+        object.push(".o");
+        PathBuf::from(object)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedEntry {
+    hash: String,
+    dependencies: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    toolchain: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    objects: Vec<SerializedObject>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedObject {
+    source: PathBuf,
+    hash: String,
+    dependencies: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnySerializedEntry {
+    // the cache used to only store the hash
+    Legacy(String),
+    Current(SerializedEntry),
+}
+
+// bump when the meaning of the serialized entries changes, older caches are then discarded
+const CACHE_VERSION: u32 = 1;
+const HASH_ALGORITHM: &str = "blake3";
+
+#[derive(Serialize, Deserialize)]
+struct SerializedCache {
+    version: u32,
+    hash_algorithm: String,
+    // sorted so that saving the same entries always produces the same file
+    entries: BTreeMap<PathBuf, SerializedEntry>,
+}
+
+/// Parses the cache file, returns `None` if it is unreadable or incompatible and should be discarded
+fn parse_cache(contents: &str) -> Option<HashMap<PathBuf, SerializedEntry>> {
+    let value = match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(ok) => ok,
+        Err(e) => {
+            log::warn!("discarding unreadable cache\n  {e}");
+            return None;
+        }
+    };
+
+    // caches before versioning were a flat map of sources to their entries
+    if !value.get("version").is_some_and(|v| v.is_u64()) {
+        log::debug!("migrating an unversioned cache");
+        let legacy = match serde_json::from_value::<HashMap<PathBuf, AnySerializedEntry>>(value) {
+            Ok(ok) => ok,
+            Err(e) => {
+                log::warn!("discarding unreadable cache\n  {e}");
+                return None;
+            }
+        };
+        let entries = legacy.into_iter().map(|(source, entry)| {
+            let entry = match entry {
+                AnySerializedEntry::Legacy(hash) => SerializedEntry {
+                    hash,
+                    dependencies: Vec::new(),
+                    toolchain: None,
+                    objects: Vec::new(),
+                },
+                AnySerializedEntry::Current(entry) => entry,
+            };
+            (source, entry)
+        });
+        return Some(entries.collect());
+    }
+
+    let cache = match serde_json::from_value::<SerializedCache>(value) {
+        Ok(ok) => ok,
+        Err(e) => {
+            log::warn!("discarding unreadable cache\n  {e}");
+            return None;
+        }
+    };
+    if cache.version != CACHE_VERSION || cache.hash_algorithm != HASH_ALGORITHM {
+        log::info!(
+            "discarding cache version {} using {}, rebuilding everything",
+            cache.version,
+            cache.hash_algorithm
+        );
+        return None;
+    }
+    Some(cache.entries.into_iter().collect())
 }
 
 pub struct Database {
     cache_file: PathBuf,
     out_dir: PathBuf,
     cache: HashMap<PathBuf, Rc<CacheEntry>>,
+    // versions of the compiler commands queried during this run
+    toolchains: HashMap<String, Option<String>>,
+    pub stats: BuildStats,
+}
+
+/// How many of the files passed to `build_files` needed a rebuild
+#[derive(Default, Debug, Clone, Copy)]
+pub struct BuildStats {
+    pub rebuilt: usize,
+    pub cached: usize,
+    // the rebuilt targets which the compiler rejected
+    pub failed: usize,
 }
 
 impl Database {
     pub fn new(cache_file: PathBuf, out_dir: PathBuf) -> fs::Result<Database> {
         let parsed = if cache_file.exists() {
             let loaded = fs::read_to_string(&cache_file)?;
-            let deserialized =
-                serde_json::from_str::<HashMap<PathBuf, String>>(&loaded).map_err(|e| {
-                    log::error!("failed to deserialize cache\n  {e}");
-                    AlreadyReported
-                })?;
-            deserialized
+            parse_cache(&loaded)
+                .unwrap_or_default()
                 .into_iter()
-                .map(|(k, v)| {
-                    let hash = u128::from_str_radix(&v, 16).unwrap();
-                    let entry = CacheEntry::from_serialized(&k, hash, &out_dir);
+                .map(|(k, serialized)| {
+                    // a zero hash never matches, so a malformed one just causes a rebuild
+                    let hash = u128::from_str_radix(&serialized.hash, 16).unwrap_or_else(|_| {
+                        log::debug!("malformed hash of `{}` in the cache", fs::display(&k));
+                        0
+                    });
+                    let mut entry = CacheEntry::from_serialized(
+                        &k,
+                        hash,
+                        serialized.dependencies,
+                        serialized.toolchain,
+                        &out_dir,
+                    );
+                    // a malformed object hash only causes the object to be recompiled
+                    let objects = serialized.objects.into_iter().map(|object| ObjectFile {
+                        hash: u128::from_str_radix(&object.hash, 16).unwrap_or(0),
+                        source: object.source,
+                        dependencies: object.dependencies,
+                    });
+                    *entry.objects.get_mut() = objects.collect();
                     (k, Rc::new(entry))
                 })
                 .collect()
@@ -64,111 +241,1059 @@ impl Database {
             cache_file,
             out_dir,
             cache: parsed,
+            toolchains: HashMap::new(),
+            stats: BuildStats::default(),
         })
     }
+    /// Removes the entry from the cache and deletes its build artifacts
+    pub fn remove(&mut self, source_file: &Path) -> Option<Rc<CacheEntry>> {
+        let entry = self.cache.remove(source_file)?;
+        for path in [&entry.binary, &entry.build_log] {
+            if path.exists() {
+                _ = fs::remove_file(path);
+            }
+        }
+        if entry.objects_dir.exists() {
+            _ = fs::remove_dir_all(&entry.objects_dir);
+        }
+        Some(entry)
+    }
+    /// Returns the source files along with their known dependencies and samples
+    pub fn input_files(&self, source_files: &[PathBuf]) -> Vec<PathBuf> {
+        let mut files = source_files.to_vec();
+        for entry in source_files.iter().filter_map(|s| self.cache.get(s)) {
+            files.extend(entry.dependencies.borrow().iter().cloned());
+            files.extend(entry.samples.iter().cloned());
+        }
+        files
+    }
+    /// Returns the entries of the source files without validating or building them
+    pub fn entries(&self, source_files: &[PathBuf]) -> Vec<Rc<CacheEntry>> {
+        source_files
+            .iter()
+            .map(|source_file| match self.cache.get(source_file) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let entry = CacheEntry::from_serialized(
+                        source_file,
+                        0,
+                        Vec::new(),
+                        None,
+                        &self.out_dir,
+                    );
+                    Rc::new(entry)
+                }
+            })
+            .collect()
+    }
     pub fn new_empty(cache_file: PathBuf, out_dir: PathBuf) -> Database {
         Database {
             cache_file,
             out_dir,
             cache: HashMap::new(),
+            toolchains: HashMap::new(),
+            stats: BuildStats::default(),
         }
     }
-    pub fn build_file(
+    pub fn build_files(
+        &mut self,
+        source_files: &[PathBuf],
+        args: &BuildOpts,
+        jobs: NonZeroUsize,
+        dry_run: bool,
+    ) -> Vec<fs::Result<Rc<CacheEntry>>> {
+        // entries which need to be compiled stay `None` until they are,
+        // with --fail-fast those which never get compiled are left out
+        let mut results = Vec::with_capacity(source_files.len());
+        let mut pending = Vec::new();
+        for source_file in source_files {
+            match self.prepare_build(source_file, args) {
+                Ok((entry, true)) if !dry_run && self.fetch_shared(&entry, args) => {
+                    self.stats.cached += 1;
+                    results.push(Some(Ok(entry)));
+                }
+                Ok((entry, true)) => {
+                    self.stats.rebuilt += 1;
+                    // the objects are hashed with the current compiler version, the entry
+                    // only records it once the build succeeds
+                    let pending_entry = CacheEntry::clone(&entry);
+                    pending_entry
+                        .toolchain
+                        .replace(self.toolchain(source_file, args));
+                    pending.push((results.len(), pending_entry));
+                    results.push(None);
+                }
+                Ok((entry, false)) => {
+                    self.stats.cached += 1;
+                    results.push(Some(Ok(entry)));
+                }
+                Err(e) => {
+                    results.push(Some(Err(e)));
+                    if args.fail_fast {
+                        pending.clear();
+                        break;
+                    }
+                }
+            }
+        }
+
+        if dry_run {
+            for (index, pending_entry) in pending {
+                let plan = build_plan(&pending_entry, args);
+                results[index] = Some(plan.map(|plan| {
+                    for unit in plan.units.iter().filter(|u| u.reused.is_none()) {
+                        fs::print_dry_run(&unit.command);
+                    }
+                    fs::print_dry_run(&plan.link);
+                    self.cache[&source_files[index]].clone()
+                }));
+            }
+            return results.into_iter().flatten().collect();
+        }
+
+        let progress = Progress::start(pending.len(), "built");
+        let compiled = compile_files(pending, args, jobs, &progress);
+        drop(progress);
+        for (index, result) in compiled {
+            let entry = self.cache[&source_files[index]].clone();
+            let toolchain = self.toolchain(&entry.source, args);
+            // only record the new hash once the binary is actually built, it is recomputed
+            // because the build may have discovered a different set of dependencies
+            let source_hash = result.and_then(|compiled| {
+                entry.dependencies.replace(compiled.dependencies);
+                entry.objects.replace(compiled.objects);
+                source_hash(&entry, args, toolchain.as_deref())
+            });
+            results[index] = Some(source_hash.map(|hash| {
+                entry.source_hash.set(hash);
+                entry.toolchain.replace(toolchain);
+                if let Some(dir) = &args.cache_dir {
+                    store_shared(dir, &entry, args);
+                }
+                entry
+            }));
+        }
+
+        let skipped = source_files.len() - results.iter().flatten().count();
+        if skipped > 0 && interrupt::interrupted() {
+            log::info!("Skipped {skipped} targets after the interrupt");
+        } else if skipped > 0 {
+            log::info!("Skipped {skipped} targets after the first error");
+        }
+
+        results.into_iter().flatten().collect()
+    }
+    /// Validates the source file and checks whether it needs to be rebuilt
+    fn prepare_build(
         &mut self,
         source_file: &Path,
         args: &BuildOpts,
-    ) -> fs::Result<Rc<CacheEntry>> {
+    ) -> fs::Result<(Rc<CacheEntry>, bool)> {
         if source_file.is_absolute() {
             report("path is absolute", source_file).to_result()?;
         }
+        if !source_file.exists() {
+            let resolved = std::path::absolute(source_file);
+            let resolved = resolved.as_deref().unwrap_or(source_file);
+            let similar = similar_file_names(source_file)
+                .iter()
+                .map(|p| format!("`{}`", fs::display(p)))
+                .collect::<Vec<_>>();
+            match similar.is_empty() {
+                true => bail!("source file not found `{}`", resolved.display()),
+                false => bail!(
+                    "source file not found `{}`, did you mean {}?",
+                    resolved.display(),
+                    similar.join(" or ")
+                ),
+            }
+        }
         let extension = source_file.extension().unwrap_or_default().as_bytes();
         if !matches!(extension, b"c" | b"cpp" | b"h" | b"hpp") {
             report("must be a C/C++ source file", source_file).to_result()?;
         }
 
-        let entry = self
-            .cache
-            .entry(source_file.to_owned())
-            .or_insert_with(|| Rc::new(CacheEntry::from_serialized(source_file, 0, &self.out_dir)));
-
-        let mut source_hash = hash_file(&entry.source)?;
-        append_hash(&mut source_hash, &args.defines);
-        append_hash(&mut source_hash, &args.compiler_args);
-        append_hash(&mut source_hash, &args.no_default_args);
-
-        if entry.source_hash.get() != source_hash {
-            entry.source_hash.set(source_hash);
-            log::info!("building {}", entry.source.display());
-            compile_file(&entry, args)?;
+        let toolchain = self.toolchain(source_file, args);
+        let entry = self.cache.entry(source_file.to_owned()).or_insert_with(|| {
+            let entry =
+                CacheEntry::from_serialized(source_file, 0, Vec::new(), None, &self.out_dir);
+            Rc::new(entry)
+        });
+
+        let changed = match (entry.toolchain.borrow().as_deref(), toolchain.as_deref()) {
+            (Some(previous), Some(current)) if previous != current => Some(format!(
+                "the compiler changed from `{previous}` to `{current}`"
+            )),
+            _ => None,
+        };
+        if let Some(reason) = changed {
+            log::info!("building {}, {reason}", fs::display(&entry.source));
+            Ok((entry.clone(), true))
+        } else if entry.source_hash.get() != source_hash(entry, args, toolchain.as_deref())? {
+            log::info!("building {}", fs::display(&entry.source));
+            Ok((entry.clone(), true))
+        } else if !entry.binary.exists() {
+            log::info!(
+                "building {}, the binary is missing",
+                fs::display(&entry.source)
+            );
+            Ok((entry.clone(), true))
         } else {
-            log::debug!("Skipping build `{}` unchanged", entry.source.display());
+            log::debug!("Skipping build `{}` unchanged", fs::display(&entry.source));
+            Ok((entry.clone(), false))
+        }
+    }
+    /// Copies the binary from the --cache-dir if it was built from the same sources with the
+    /// same options, returns whether it was found
+    fn fetch_shared(&mut self, entry: &CacheEntry, args: &BuildOpts) -> bool {
+        let Some(dir) = &args.cache_dir else {
+            return false;
+        };
+        let toolchain = self.toolchain(&entry.source, args);
+        let Ok(key) = shared_key(entry, args, toolchain.as_deref()) else {
+            return false;
+        };
+        let shared = dir.join(format!("{key:032x}"));
+        let Ok(contents) = std::fs::read(shared.join(SHARED_ENTRY_FILE)) else {
+            log::debug!(
+                "`{}` is not in the cache directory",
+                fs::display(&entry.source)
+            );
+            return false;
+        };
+        let Ok(stored) = serde_json::from_slice::<SharedEntry>(&contents) else {
+            log::debug!("malformed entry `{}`", fs::display(&shared));
+            return false;
+        };
+        // the key doesn't cover the dependencies, they are only known after a build
+        let hash = build_hash(
+            &entry.source,
+            &stored.dependencies,
+            args,
+            toolchain.as_deref(),
+        );
+        if hash.map(|hash| format!("{hash:032x}")).ok().as_ref() != Some(&stored.hash) {
+            log::debug!(
+                "the dependencies of `{}` differ from the cache directory",
+                fs::display(&entry.source)
+            );
+            return false;
         }
 
-        Ok(entry.clone())
+        _ = fs::create_dir_all(entry.binary.parent().unwrap());
+        _ = std::fs::remove_file(&entry.binary);
+        if let Err(e) = link_or_copy(&shared.join("binary"), &entry.binary) {
+            log::warn!(
+                "failed to copy from the cache directory `{}`: {e}",
+                fs::display(&shared)
+            );
+            return false;
+        }
+        log::info!(
+            "copied the binary of {} from the cache directory",
+            fs::display(&entry.source)
+        );
+        entry.source_hash.set(hash.unwrap());
+        entry.dependencies.replace(stored.dependencies);
+        entry.toolchain.replace(toolchain);
+        true
+    }
+    /// Returns the version of the compiler for the source with --compiler-version-guard,
+    /// it is queried once per run for every compiler command
+    fn toolchain(&mut self, source_file: &Path, args: &BuildOpts) -> Option<String> {
+        if !args.compiler_version_guard {
+            return None;
+        }
+        let compiler = args.compiler(source_file);
+        self.toolchains
+            .entry(compiler.to_owned())
+            .or_insert_with(|| compiler_version(compiler))
+            .clone()
     }
     pub fn save_to_file(&self) -> fs::Result<()> {
         let raw = self
             .cache
             .iter()
             .map(|(k, v)| {
-                let hash = format!("{:032x}", v.source_hash.get());
-                (k.clone(), hash)
+                let entry = SerializedEntry {
+                    hash: format!("{:032x}", v.source_hash.get()),
+                    dependencies: v.dependencies.borrow().clone(),
+                    toolchain: v.toolchain.borrow().clone(),
+                    objects: v
+                        .objects
+                        .borrow()
+                        .iter()
+                        .map(|object| SerializedObject {
+                            source: object.source.clone(),
+                            hash: format!("{:032x}", object.hash),
+                            dependencies: object.dependencies.clone(),
+                        })
+                        .collect(),
+                };
+                (k.clone(), entry)
             })
-            .collect::<HashMap<PathBuf, String>>();
-        let serialized = serde_json::ser::to_string_pretty(&raw).unwrap();
+            .collect::<BTreeMap<PathBuf, SerializedEntry>>();
+        let cache = SerializedCache {
+            version: CACHE_VERSION,
+            hash_algorithm: HASH_ALGORITHM.to_owned(),
+            entries: raw,
+        };
+        let serialized = serde_json::ser::to_string_pretty(&cache).unwrap();
 
         fs::write(&self.cache_file, serialized.as_bytes())
     }
 }
 
-fn compile_file(paths: &CacheEntry, args: &BuildOpts) -> fs::Result<()> {
+/// Compiles the entries on up to `jobs` threads, the results are returned in an unspecified order
+///
+/// With --fail-fast no new compilations are started after one fails and the results are incomplete
+fn compile_files(
+    pending: Vec<(usize, CacheEntry)>,
+    args: &BuildOpts,
+    jobs: NonZeroUsize,
+    progress: &Progress,
+) -> Vec<(usize, fs::Result<CompiledTarget>)> {
+    let threads = jobs.get().min(pending.len());
+    let queue = Mutex::new(pending.into_iter());
+    let results = Mutex::new(Vec::new());
+    let aborted = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                if aborted.load(Ordering::Relaxed) || interrupt::interrupted() {
+                    break;
+                }
+                let Some((index, entry)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                progress.set_message(fs::display(&entry.source).to_string());
+                let result = compile_file(&entry, args);
+                progress.inc();
+                if result.is_err() && args.fail_fast {
+                    aborted.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Files next to a missing source whose names are likely a typo of its name
+fn similar_file_names(source_file: &Path) -> Vec<PathBuf> {
+    let Some(name) = source_file.file_name().and_then(|n| n.to_str()) else {
+        return Vec::new();
+    };
+    let parent = source_file.parent().unwrap_or(Path::new(""));
+    let dir = match parent.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => parent,
+    };
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut similar = read_dir
+        .flatten()
+        .filter_map(|entry| {
+            let other = entry.file_name().into_string().ok()?;
+            let distance = strsim::levenshtein(name, &other);
+            (distance <= max_distance).then(|| (distance, parent.join(other)))
+        })
+        .collect::<Vec<_>>();
+    similar.sort();
+    similar.into_iter().take(3).map(|(_, path)| path).collect()
+}
+
+/// The result of a successful build
+struct CompiledTarget {
+    dependencies: Vec<PathBuf>,
+    objects: Vec<ObjectFile>,
+}
+
+/// Compiles the translation units whose objects are out of date and links the binary
+fn compile_file(paths: &CacheEntry, args: &BuildOpts) -> fs::Result<CompiledTarget> {
     _ = fs::create_dir_all(paths.binary.parent().unwrap());
     if paths.binary.exists() {
         _ = fs::remove_file(&paths.binary);
     }
-    let mut builder = std::process::Command::new("g++");
-    if args.no_default_args {
-        builder.args(&["-std=c++11", "-Wall", "-pedantic"]);
+    let plan = build_plan(paths, args)?;
+
+    let mut diagnostics = Vec::new();
+    let mut objects = Vec::new();
+    let mut success = true;
+    for unit in plan.units {
+        if let Some(reused) = unit.reused {
+            log::debug!("reusing `{}`", fs::display(&unit.object));
+            objects.push(reused);
+            continue;
+        }
+
+        _ = fs::create_dir_all(unit.object.parent().unwrap());
+        success = run_compiler(unit.command, &mut diagnostics, args)?;
+        if !success {
+            break;
+        }
+        let depfile = fs::read(&unit.object.with_extension("d"))?;
+        let dependencies = parse_depfile(&depfile)
+            .into_iter()
+            .filter(|path| *path != unit.source)
+            .collect::<Vec<_>>();
+        let toolchain = paths.toolchain.borrow();
+        let hash = object_hash(
+            &unit.source,
+            &dependencies,
+            &unit.args,
+            toolchain.as_deref(),
+        )?;
+        objects.push(ObjectFile {
+            source: unit.source,
+            hash,
+            dependencies,
+        });
+    }
+    if success {
+        success = run_compiler(plan.link, &mut diagnostics, args)?;
     }
+
+    if !diagnostics.is_empty() {
+        _ = fs::create_dir_all(paths.build_log.parent().unwrap());
+        _ = fs::write(&paths.build_log, &diagnostics);
+    } else if paths.build_log.exists() {
+        _ = fs::remove_file(&paths.build_log);
+    }
+
+    if !success {
+        // written at once so that the output of parallel builds doesn't interleave
+        _ = progress::suspend(|| std::io::stderr().write_all(&diagnostics));
+        bail!(
+            Build,
+            "building `{}` failed, the compiler output is saved in `{}`",
+            fs::display(&paths.source),
+            fs::display(&paths.build_log)
+        );
+    }
+    if !diagnostics.is_empty() {
+        log::info!(
+            "`{}` built with warnings, see `{}`",
+            fs::display(&paths.source),
+            fs::display(&paths.build_log)
+        );
+    }
+    fs::check_exists(&paths.binary)?;
+
+    // the other translation units are dependencies of the target as well
+    let mut dependencies = Vec::new();
+    for object in &objects {
+        if object.source != paths.source {
+            dependencies.push(object.source.clone());
+        }
+        dependencies.extend(object.dependencies.iter().cloned());
+    }
+    dependencies.sort();
+    dependencies.dedup();
+    dependencies.retain(|path| *path != paths.source);
+    log::trace!(
+        "`{}` depends on {dependencies:?}",
+        fs::display(&paths.source)
+    );
+    Ok(CompiledTarget {
+        dependencies,
+        objects,
+    })
+}
+
+/// The delay before the first retry of --compile-retries, it grows with every retry
+const COMPILE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Runs the compiler and collects what it printed, returns whether it succeeded
+fn run_compiler(
+    mut builder: std::process::Command,
+    diagnostics: &mut Vec<u8>,
+    args: &BuildOpts,
+) -> fs::Result<bool> {
+    print_args(&builder);
+    let program = builder.get_program().to_string_lossy().into_owned();
+    let start = Instant::now();
+    let mut retries = 0;
+    let output = loop {
+        let result = match args.compiler_timeout {
+            Some(timeout) => output_with_timeout(&mut builder, timeout),
+            None => builder.output().map(Some),
+        };
+        match result {
+            Ok(Some(output)) => break output,
+            Ok(None) => bail!(
+                Build,
+                "compilation timed out after {}s, killed `{}`",
+                args.compiler_timeout.unwrap().as_secs_f64(),
+                fs::command_line(&builder)
+            ),
+            // likely a transient problem of the filesystem, unlike an exit code
+            Err(e) if retries < args.compile_retries => {
+                retries += 1;
+                let delay = COMPILE_RETRY_DELAY * retries;
+                log::warn!(
+                    "{program} failed: {e}, retrying in {}s ({retries}/{})",
+                    delay.as_secs_f64(),
+                    args.compile_retries
+                );
+                std::thread::sleep(delay);
+            }
+            Err(e) => bail!("{program} failed: {e}"),
+        }
+    };
+    if args.verbose_compiler {
+        let seconds = start.elapsed().as_secs_f64();
+        log::info!("Ran `{}` in {seconds:.2}s", fs::command_line(&builder));
+    }
+    diagnostics.extend_from_slice(&output.stdout);
+    diagnostics.extend_from_slice(&output.stderr);
+    Ok(output.status.success())
+}
+
+/// Runs the command like `Command::output`, but kills it once it runs longer than the timeout
+/// and returns `None`
+fn output_with_timeout(
+    builder: &mut std::process::Command,
+    timeout: Duration,
+) -> std::io::Result<Option<std::process::Output>> {
+    let mut child = builder
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // the output is not limited, the flag is never set
+    let unlimited = Arc::new(AtomicBool::new(false));
+    let stdout = crate::spawn_reader(child.stdout.take().unwrap(), u64::MAX, unlimited.clone());
+    let stderr = crate::spawn_reader(child.stderr.take().unwrap(), u64::MAX, unlimited.clone());
+
+    let status = match fs::wait_timeout(&mut child, Some(timeout), &unlimited) {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            _ = child.kill();
+            _ = child.wait();
+            return Ok(None);
+        }
+        Err(e) => {
+            _ = child.kill();
+            return Err(e);
+        }
+    };
+    let read = |reader: std::thread::JoinHandle<std::io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .expect("Reading thread panicked!")
+            .unwrap_or_default()
+    };
+    Ok(Some(std::process::Output {
+        status,
+        stdout: read(stdout),
+        stderr: read(stderr),
+    }))
+}
+
+/// The commands which build a target
+struct BuildPlan {
+    units: Vec<PlannedObject>,
+    link: std::process::Command,
+}
+
+/// The compilation of a single translation unit to its object file
+struct PlannedObject {
+    source: PathBuf,
+    object: PathBuf,
+    command: std::process::Command,
+    // the command line which is part of the object hash
+    args: Vec<OsString>,
+    // the object of the last build if it is still up to date
+    reused: Option<ObjectFile>,
+}
+
+/// Splits --options like the shell would, so that a quoted option can contain spaces
+fn split_compiler_args(compiler_args: &str) -> fs::Result<Vec<String>> {
+    match shlex::split(compiler_args) {
+        Some(options) => Ok(options),
+        None => {
+            bail!("the compiler options `{compiler_args}` have an unterminated quote or escape")
+        }
+    }
+}
+
+/// Splits the build of the target into compiling every translation unit and linking them,
+/// the translation units are the target and any source files among the compiler options
+fn build_plan(paths: &CacheEntry, args: &BuildOpts) -> fs::Result<BuildPlan> {
+    if let Some(path) = flags_file(&paths.source, args) {
+        log::debug!("applying the flags in `{}`", fs::display(&path));
+    }
+    let mut options = split_compiler_args(args.compiler_args.as_deref().unwrap_or(""))?;
+    options.extend(file_flags(&paths.source, args)?);
+
+    let mut sources = vec![paths.source.clone()];
+    options.retain(|option| {
+        let path = Path::new(option);
+        let is_source = matches!(
+            path.extension().unwrap_or_default().as_bytes(),
+            b"c" | b"cc" | b"cpp" | b"cxx"
+        ) && path.is_file();
+        if is_source && !sources.iter().any(|s| s == path) {
+            sources.push(path.to_owned());
+        }
+        !is_source
+    });
+
+    let toolchain = paths.toolchain.borrow();
+    let previous = paths.objects.borrow();
+    let mut units = Vec::new();
+    for source in sources {
+        let object = paths.object_path(&source);
+        let command = object_command(&source, &object, &options, args)?;
+        let command_line = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|a| a.to_owned())
+            .collect::<Vec<_>>();
+
+        let mut reused = None;
+        if let Some(previous) = previous.iter().find(|o| o.source == source) {
+            let dependencies = &previous.dependencies;
+            let hash = object_hash(&source, dependencies, &command_line, toolchain.as_deref())?;
+            if hash == previous.hash && object.is_file() {
+                reused = Some(previous.clone());
+            }
+        }
+        units.push(PlannedObject {
+            source,
+            object,
+            command,
+            args: command_line,
+            reused,
+        });
+    }
+
+    let mut link = compiler_command(args.linker(units.iter().map(|u| u.source.as_path())))?;
+    link.args(args.profile_args())
+        .args(units.iter().map(|u| &u.object))
+        // libraries have to come after the objects which use them
+        .args(options.iter().filter(|o| !o.starts_with("-D")))
+        .args(args.libraries.iter().flat_map(|lib| ["-l", lib]))
+        .arg("-o")
+        .arg(&paths.binary);
+    Ok(BuildPlan { units, link })
+}
+
+/// The command compiling a translation unit to an object file
+fn object_command(
+    source: &Path,
+    object: &Path,
+    options: &[String],
+    args: &BuildOpts,
+) -> fs::Result<std::process::Command> {
+    let mut builder = compiler_command(args.compiler(source))?;
+    if !args.no_default_args {
+        builder.args(args.default_args(source));
+    }
+    if args.werror {
+        builder.arg("-Werror");
+    }
+    builder.args(args.profile_args());
     for define in &args.defines {
         builder.arg("-D");
         builder.arg(define);
     }
+    for dir in &args.include_dirs {
+        builder.arg("-I");
+        builder.arg(dir);
+    }
     builder
-        .args(
-            args.compiler_args
-                .as_deref()
-                .unwrap_or("")
-                .split_ascii_whitespace(),
-        )
+        .args(options.iter().filter(|o| !is_link_option(o)))
+        .arg("-c")
+        .arg("-MMD")
+        .arg("-MF")
+        .arg(object.with_extension("d"))
         .arg("-o")
-        .arg(&paths.binary)
-        .arg(&paths.source);
+        .arg(object)
+        .arg(source);
+    Ok(builder)
+}
 
-    print_args(&builder);
-    check_status("g++", builder.status())?;
-    fs::check_exists(&paths.binary)
+/// Options which only matter when linking, the compiler warns that they are unused otherwise
+fn is_link_option(option: &str) -> bool {
+    ["-l", "-L", "-Wl,"].iter().any(|p| option.starts_with(p))
 }
 
-fn hash_file(path: &Path) -> fs::Result<u128> {
-    let input = fs::open(path)?;
+/// Starts a command from a compiler split by whitespace like `ccache g++`
+fn compiler_command(compiler: &str) -> fs::Result<std::process::Command> {
+    let mut compiler = compiler.split_ascii_whitespace();
+    let Some(program) = compiler.next() else {
+        bail!("the compiler command is empty");
+    };
+    let mut builder = std::process::Command::new(program);
+    builder.args(compiler);
+    Ok(builder)
+}
+
+/// Hashes a translation unit, the headers it includes and the command compiling it
+fn object_hash(
+    source: &Path,
+    dependencies: &[PathBuf],
+    command_line: &[OsString],
+    toolchain: Option<&str>,
+) -> fs::Result<u128> {
     let mut hasher = blake3::Hasher::new();
+    hash_file(&mut hasher, source)?;
+    for dependency in dependencies {
+        append_hash(&mut hasher, dependency);
+        if dependency.is_file() {
+            hash_file(&mut hasher, dependency)?;
+        }
+    }
+    append_hash(&mut hasher, &command_line);
+    append_hash(&mut hasher, &toolchain);
+    Ok(finalize_hash(&hasher))
+}
+
+/// Returns the `<source>.flags` file with --per-file-flags if it exists
+fn flags_file(source: &Path, args: &BuildOpts) -> Option<PathBuf> {
+    if !args.per_file_flags {
+        return None;
+    }
+    let mut path = source.as_os_str().to_owned();
+    path.push(".flags");
+    Some(PathBuf::from(path)).filter(|p| p.is_file())
+}
+
+/// Reads the compiler options for a single source from its flags file
+fn file_flags(source: &Path, args: &BuildOpts) -> fs::Result<Vec<String>> {
+    let Some(path) = flags_file(source, args) else {
+        return Ok(Vec::new());
+    };
+    let contents = fs::read_to_string(&path)?;
+    Ok(contents
+        .split_ascii_whitespace()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Parses the prerequisites of the first rule in a make depfile written by `-MMD`
+fn parse_depfile(contents: &[u8]) -> Vec<PathBuf> {
+    let Some(colon) = contents.find(": ") else {
+        return Vec::new();
+    };
+
+    let mut dependencies = Vec::new();
+    let mut current = Vec::new();
+    let mut bytes = contents[colon + 2..].iter().copied().peekable();
+    loop {
+        let byte = bytes.next();
+        match byte {
+            // line continuation
+            Some(b'\\') if bytes.peek() == Some(&b'\n') => {
+                bytes.next();
+            }
+            Some(b'\\') if bytes.peek() == Some(&b' ') => {
+                bytes.next();
+                current.push(b' ');
+                continue;
+            }
+            Some(b'$') if bytes.peek() == Some(&b'$') => {
+                bytes.next();
+                current.push(b'$');
+                continue;
+            }
+            Some(b' ' | b'\t' | b'\r') => {}
+            Some(b'\n') | None => {}
+            Some(byte) => {
+                current.push(byte);
+                continue;
+            }
+        }
+
+        if !current.is_empty() {
+            let path = OsString::from_vec(std::mem::take(&mut current));
+            dependencies.push(PathBuf::from(path));
+        }
+        // a newline without a continuation ends the rule
+        if matches!(byte, Some(b'\n') | None) {
+            break;
+        }
+    }
+    dependencies
+}
+
+/// Hashes the source file, its dependencies and the build options
+fn source_hash(entry: &CacheEntry, args: &BuildOpts, toolchain: Option<&str>) -> fs::Result<u128> {
+    build_hash(&entry.source, &entry.dependencies.borrow(), args, toolchain)
+}
+
+fn build_hash(
+    source: &Path,
+    dependencies: &[PathBuf],
+    args: &BuildOpts,
+    toolchain: Option<&str>,
+) -> fs::Result<u128> {
+    let mut hasher = blake3::Hasher::new();
+    hash_file(&mut hasher, source)?;
+    for dependency in dependencies {
+        append_hash(&mut hasher, dependency);
+        // a missing dependency changes the hash and forces a rebuild, which will report the error
+        if dependency.is_file() {
+            hash_file(&mut hasher, dependency)?;
+        }
+    }
+    append_hash(&mut hasher, &args.compiler(source));
+    append_hash(&mut hasher, &args.defines);
+    append_hash(&mut hasher, &args.compiler_args);
+    append_hash(&mut hasher, &args.include_dirs);
+    append_hash(&mut hasher, &args.libraries);
+    append_hash(&mut hasher, &args.no_default_args);
+    append_hash(&mut hasher, &args.werror);
+    append_hash(&mut hasher, &args.profile_args());
+    append_hash(&mut hasher, &file_flags(source, args)?);
+    append_hash(&mut hasher, &toolchain);
+    Ok(finalize_hash(&hasher))
+}
+
+/// The file next to the binary in a directory of the --cache-dir
+const SHARED_ENTRY_FILE: &str = "entry.json";
 
+/// Records what a binary in the --cache-dir was built from
+#[derive(Serialize, Deserialize)]
+struct SharedEntry {
+    hash: String,
+    dependencies: Vec<PathBuf>,
+}
+
+/// Names the directory of a target in the --cache-dir, the hash of its sources and options
+/// without the dependencies
+fn shared_key(entry: &CacheEntry, args: &BuildOpts, toolchain: Option<&str>) -> fs::Result<u128> {
+    build_hash(&entry.source, &[], args, toolchain)
+}
+
+/// Stores the built binary in the --cache-dir for other roots, failures only warn
+fn store_shared(dir: &Path, entry: &CacheEntry, args: &BuildOpts) {
+    let toolchain = entry.toolchain.borrow();
+    let Ok(key) = shared_key(entry, args, toolchain.as_deref()) else {
+        return;
+    };
+    let shared = dir.join(format!("{key:032x}"));
+    let stored = SharedEntry {
+        hash: format!("{:032x}", entry.source_hash.get()),
+        dependencies: entry.dependencies.borrow().clone(),
+    };
+    // written elsewhere and renamed so that other roots never see a partial entry
+    let temporary = dir.join(format!("{key:032x}.{}.tmp", std::process::id()));
+    let store = || -> std::io::Result<()> {
+        std::fs::create_dir_all(&temporary)?;
+        link_or_copy(&entry.binary, &temporary.join("binary"))?;
+        let serialized = serde_json::to_string_pretty(&stored).unwrap();
+        std::fs::write(temporary.join(SHARED_ENTRY_FILE), serialized)?;
+        if shared.exists() {
+            std::fs::remove_dir_all(&shared)?;
+        }
+        std::fs::rename(&temporary, &shared)
+    };
+    match store() {
+        Ok(()) => log::debug!(
+            "stored `{}` in the cache directory",
+            fs::display(&entry.source)
+        ),
+        Err(e) => {
+            _ = std::fs::remove_dir_all(&temporary);
+            log::warn!(
+                "failed to store `{}` in the cache directory: {e}",
+                fs::display(&entry.source)
+            );
+        }
+    }
+}
+
+/// Hard links the file if possible, the link shares the permissions of the original
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::hard_link(from, to).or_else(|_| std::fs::copy(from, to).map(drop))
+}
+
+/// Returns the first line printed by `<compiler> --version`
+fn compiler_version(compiler: &str) -> Option<String> {
+    let mut compiler = compiler.split_ascii_whitespace();
+    let mut builder = std::process::Command::new(compiler.next()?);
+    builder.args(compiler).arg("--version");
+
+    print_args(&builder);
+    let output = match builder.output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            log::warn!(
+                "querying the compiler version failed with {}",
+                output.status
+            );
+            return None;
+        }
+        Err(e) => {
+            log::warn!("querying the compiler version failed\n  {e}");
+            return None;
+        }
+    };
+    let version = output
+        .stdout
+        .lines()
+        .next()?
+        .to_str_lossy()
+        .trim()
+        .to_owned();
+    log::debug!("compiler version `{version}`");
+    Some(version)
+}
+
+fn hash_file(hasher: &mut blake3::Hasher, path: &Path) -> fs::Result<()> {
+    let input = fs::open(path)?;
     hasher
         .update_reader(&input)
         .map_err(|e| report_io_error("failed to update_reader", path, e))?;
+    Ok(())
+}
 
+fn finalize_hash(hasher: &blake3::Hasher) -> u128 {
     let mut buf = [0; 16];
     hasher.finalize_xof().fill(&mut buf);
-    Ok(u128::from_le_bytes(buf))
+    u128::from_le_bytes(buf)
 }
 
-fn append_hash<T: Hash>(prev_hash: &mut u128, value: &T) {
-    let hash = {
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        value.hash(&mut hasher);
-        hasher.finish()
-    } as u128;
-    let extended = hash | (hash << 32);
-    *prev_hash ^= extended;
+/// Feeds the bytes produced by `Hash` implementations into a blake3 hasher,
+/// the length prefixes written by std keep sequences of values unambiguous
+struct Blake3Writer<'a>(&'a mut blake3::Hasher);
+
+impl Hasher for Blake3Writer<'_> {
+    fn finish(&self) -> u64 {
+        unreachable!("the digest is finalized through the blake3 hasher")
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+fn append_hash<T: Hash>(hasher: &mut blake3::Hasher, value: &T) {
+    value.hash(&mut Blake3Writer(hasher));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Arguments, Command};
+    use clap::Parser;
+
+    fn build_options(args: &[&str]) -> BuildOpts {
+        let args = ["testprog", "build"].iter().chain(args);
+        match Arguments::parse_from(args).command {
+            Command::Build { build_options, .. } => build_options,
+            _ => unreachable!(),
+        }
+    }
+
+    /// An empty directory for the test, relative because targets must be
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = Path::new("target/test-tmp").join(name);
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn build_hash_depends_on_order_of_options() {
+        let dir = temp_dir("hash");
+        let source = dir.join("a.c");
+        std::fs::write(&source, "int main() {}\n").unwrap();
+        let hash = |args: &[&str]| build_hash(&source, &[], &build_options(args), None).unwrap();
+
+        assert_ne!(hash(&["-D", "A", "-D", "B"]), hash(&["-D", "B", "-D", "A"]));
+        assert_ne!(hash(&["-D", "AB"]), hash(&["-D", "BA"]));
+        // equal values must not cancel out
+        assert_ne!(hash(&["-D", "A", "-D", "A"]), hash(&[]));
+        assert_ne!(hash(&["--options=-O2 -g"]), hash(&["--options=-g -O2"]));
+        assert_eq!(hash(&["-D", "A"]), hash(&["-D", "A"]));
+
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn missing_binary_is_rebuilt() {
+        let dir = temp_dir("rebuild");
+        let source = dir.join("a.c");
+        std::fs::write(&source, "int main() {}\n").unwrap();
+        let options = build_options(&[]);
+        let jobs = NonZeroUsize::new(1).unwrap();
+        let mut cache = Database::new_empty(dir.join("cache.json"), dir.join("out"));
+        let mut build = || {
+            let mut results =
+                cache.build_files(std::slice::from_ref(&source), &options, jobs, false);
+            let entry = results.pop().unwrap().unwrap();
+            (entry.binary.clone(), cache.stats)
+        };
+
+        let (binary, stats) = build();
+        assert_eq!((stats.rebuilt, stats.cached), (1, 0));
+        let (_, stats) = build();
+        assert_eq!((stats.rebuilt, stats.cached), (1, 1));
+
+        std::fs::remove_file(&binary).unwrap();
+        let (_, stats) = build();
+        assert_eq!((stats.rebuilt, stats.cached), (2, 1));
+        assert!(binary.exists());
+
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn saving_the_same_cache_is_deterministic() {
+        let dir = temp_dir("save");
+        let cache_file = dir.join("cache.json");
+        let sources = ["a.c", "b.c", "c.c", "d.c"].map(|name| {
+            let source = dir.join(name);
+            std::fs::write(&source, "int main() {}\n").unwrap();
+            source
+        });
+        let jobs = NonZeroUsize::new(1).unwrap();
+        let mut cache = Database::new_empty(cache_file.clone(), dir.join("out"));
+        cache.build_files(&sources, &build_options(&[]), jobs, false);
+        assert_eq!(cache.cache.len(), sources.len());
+
+        cache.save_to_file().unwrap();
+        let first = std::fs::read(&cache_file).unwrap();
+        cache.save_to_file().unwrap();
+        assert_eq!(first, std::fs::read(&cache_file).unwrap());
+        // a reloaded cache has a differently ordered map
+        Database::new(cache_file.clone(), dir.join("out"))
+            .unwrap()
+            .save_to_file()
+            .unwrap();
+        assert_eq!(first, std::fs::read(&cache_file).unwrap());
+
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn defines_reach_the_compiler_intact() {
+        let define = r#"GREETING="hello 'world'" x"#;
+        let options = build_options(&["-D", define, "-D", "EMPTY="]);
+        let command = object_command(Path::new("a.c"), Path::new("a.o"), &[], &options).unwrap();
+        let args = command.get_args().collect::<Vec<_>>();
+        let position = args.iter().position(|&arg| arg == define).unwrap();
+        assert_eq!(args[position - 1], "-D");
+        assert_eq!(args[position + 1..position + 3], ["-D", "EMPTY="]);
+    }
+
+    #[test]
+    fn compiler_args_are_split_like_the_shell() {
+        let split = |str| split_compiler_args(str).unwrap();
+        assert_eq!(split("-O2  -g"), ["-O2", "-g"]);
+        assert_eq!(split(r#"-DNAME="a b" -Wall"#), ["-DNAME=a b", "-Wall"]);
+        assert_eq!(split(r#"'-DNAME="a b"'"#), [r#"-DNAME="a b""#]);
+        assert_eq!(split(r"-Isome\ dir -g"), ["-Isome dir", "-g"]);
+        assert_eq!(split(""), Vec::<String>::new());
+        assert_eq!(split("''"), [""]);
+        assert!(split_compiler_args("-DNAME=\"a").is_err());
+    }
 }
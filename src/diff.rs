@@ -0,0 +1,107 @@
+use bstr::ByteSlice;
+use nu_ansi_term::{Color, Style};
+use similar::{ChangeTag, DiffTag, TextDiff};
+
+/// Prints a unified diff of the expected and actual outputs to stderr
+pub fn print_unified_diff(expected: &[u8], actual: &[u8], color: bool) {
+    let style = |c: Color| match color {
+        true => c.normal(),
+        false => Style::new(),
+    };
+
+    let diff = TextDiff::from_lines(expected, actual);
+    eprintln!("{}", style(Color::LightRed).paint("--- expected"));
+    eprintln!("{}", style(Color::LightGreen).paint("+++ actual"));
+    for hunk in diff.unified_diff().iter_hunks() {
+        eprintln!("{}", style(Color::Cyan).paint(hunk.header().to_string()));
+        for change in hunk.iter_changes() {
+            let (sign, line_style) = match change.tag() {
+                ChangeTag::Delete => ('-', style(Color::LightRed)),
+                ChangeTag::Insert => ('+', style(Color::LightGreen)),
+                ChangeTag::Equal => (' ', Style::new()),
+            };
+            let value = change.value();
+            let line = value.strip_suffix(b"\n").unwrap_or(value).to_str_lossy();
+            eprintln!("{}", line_style.paint(format!("{sign}{line}")));
+            if change.missing_newline() {
+                eprintln!("\\ No newline at end of file");
+            }
+        }
+    }
+}
+
+/// Prints the expected and actual outputs in two aligned columns to stderr
+pub fn print_side_by_side(expected: &[u8], actual: &[u8], color: bool) {
+    // longer lines are truncated so that the columns fit in a terminal
+    const MAX_WIDTH: usize = 60;
+
+    let diff = TextDiff::from_lines(expected, actual);
+    let (old, new) = (diff.old_slices(), diff.new_slices());
+    let mut rows = Vec::new();
+    for op in diff.ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let len = old_range.len().max(new_range.len());
+        for i in 0..len {
+            let left = old.get(old_range.start + i).filter(|_| i < old_range.len());
+            let right = new.get(new_range.start + i).filter(|_| i < new_range.len());
+            let marker = match (tag, left, right) {
+                (DiffTag::Equal, ..) => ' ',
+                (_, Some(_), Some(_)) => '|',
+                (_, Some(_), None) => '<',
+                (_, None, _) => '>',
+            };
+            rows.push((
+                left.map(|l| display_line(l)),
+                marker,
+                right.map(|r| display_line(r)),
+            ));
+        }
+    }
+
+    let width = rows
+        .iter()
+        .filter_map(|(left, ..)| left.as_ref().map(|l| l.chars().count()))
+        .chain(["expected".len()])
+        .max()
+        .unwrap_or(0)
+        .min(MAX_WIDTH);
+
+    let style = |c: Color| match color {
+        true => c.normal(),
+        false => Style::new(),
+    };
+    eprintln!("{:width$}   actual", "expected");
+    for (left, marker, right) in rows {
+        let left = truncate(left.as_deref().unwrap_or(""), width);
+        let right = truncate(right.as_deref().unwrap_or(""), MAX_WIDTH);
+        let (left_style, right_style) = match marker {
+            ' ' => (Style::new(), Style::new()),
+            _ => (style(Color::LightRed), style(Color::LightGreen)),
+        };
+        eprintln!(
+            "{} {marker} {}",
+            left_style.paint(format!("{left:width$}")),
+            right_style.paint(right)
+        );
+    }
+}
+
+fn display_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    // tabs would break the alignment of the columns
+    line.to_str_lossy().replace('\t', "    ")
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    match line.chars().count() > width {
+        true => {
+            let mut truncated = line
+                .chars()
+                .take(width.saturating_sub(1))
+                .collect::<String>();
+            truncated.push('~');
+            truncated
+        }
+        false => line.to_owned(),
+    }
+}
@@ -1,39 +1,83 @@
 #![allow(dead_code)]
 
-use std::{ffi::OsString, fmt::Display, fs::OpenOptions, io::Read, path::Path};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt::Display,
+    fs::OpenOptions,
+    io::Read,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::bail;
 
+/// Why an operation failed, it is always logged where it occurs so the kind is only
+/// for callers which handle some failures differently
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct AlreadyReported;
+pub enum Error {
+    /// Nothing more specific is known about the failure
+    AlreadyReported,
+    /// A file system operation failed
+    Io,
+    /// The compiler failed to build a target
+    Build,
+    /// Some of the tests didn't pass
+    TestsFailed,
+}
+
+pub use Error::AlreadyReported;
 
-impl AlreadyReported {
-    pub fn to_result(self) -> std::result::Result<(), AlreadyReported> {
+impl Error {
+    pub fn to_result(self) -> std::result::Result<(), Error> {
         Err(self)
     }
 }
 
-impl Display for AlreadyReported {
+impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         std::fmt::Debug::fmt(&self, f)
     }
 }
 
-impl std::error::Error for AlreadyReported {}
+impl std::error::Error for Error {}
 
 macro_rules! report {
     ($result:expr, $message:literal, $path:expr) => {
         match $result {
             Ok(ok) => Ok(ok),
             Err(e) => {
-                log::error!("{} `{}`\n  {e}", $message, $path.display());
-                Err(AlreadyReported)
+                log::error!("{} `{}`\n  {e}", $message, display($path));
+                Err(Error::Io)
             }
         }
     };
 }
 
-pub type Result<T> = std::result::Result<T, AlreadyReported>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+static DISPLAY_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory which `display` shows paths relative to
+pub fn set_display_root(root: &Path) {
+    _ = DISPLAY_ROOT.set(root.to_owned());
+}
+
+/// Displays the path relative to the root if it is inside of it, so that the logs are the
+/// same wherever the project is and tools can resolve the paths against the root
+pub fn display(path: &Path) -> std::path::Display<'_> {
+    let relative = DISPLAY_ROOT
+        .get()
+        .and_then(|root| path.strip_prefix(root).ok())
+        .filter(|relative| !relative.as_os_str().is_empty());
+    relative.unwrap_or(path).display()
+}
 
 #[track_caller]
 pub fn open(path: &Path) -> Result<std::fs::File> {
@@ -65,7 +109,7 @@ pub fn check_exists(path: &Path) -> Result<()> {
     match path.exists() {
         true => Ok(()),
         false => {
-            log::error!("{} `{}`", "path does not exist", path.display());
+            log::error!("{} `{}`", "path does not exist", display(path));
             Err(AlreadyReported)
         }
     }
@@ -103,22 +147,18 @@ pub fn options_open(path: &Path, options: &std::fs::OpenOptions) -> Result<std::
     report!(res, "failed to open file", path)
 }
 
-pub fn report_custom(message: impl Display, error: impl Display) -> AlreadyReported {
+pub fn report_custom(message: impl Display, error: impl Display) -> Error {
     log::error!("{message}\n  {error}");
     AlreadyReported
 }
 
-pub fn report_io_error(
-    message: impl Display,
-    path: &Path,
-    error: std::io::Error,
-) -> AlreadyReported {
-    log::error!("{message} `{}`\n  {error}", path.display());
-    AlreadyReported
+pub fn report_io_error(message: impl Display, path: &Path, error: std::io::Error) -> Error {
+    log::error!("{message} `{}`\n  {error}", display(path));
+    Error::Io
 }
 
-pub fn report(message: impl Display, path: &Path) -> AlreadyReported {
-    log::error!("{} `{}`", message, path.display());
+pub fn report(message: impl Display, path: &Path) -> Error {
+    log::error!("{} `{}`", message, display(path));
     AlreadyReported
 }
 
@@ -130,57 +170,116 @@ pub enum TraversalResponse {
 }
 
 pub enum TraversalEvent<'a> {
-    EnterDirectory(&'a Path),
+    /// A directory and its depth, directories directly within the root have depth 0
+    EnterDirectory(&'a Path, usize),
     LeaveDirectory,
     File(&'a Path),
 }
 
-pub fn visit_files(dir: &Path, mut fun: impl FnMut(TraversalEvent) -> TraversalResponse) {
-    visit_files_impl(dir, &mut fun);
+/// Recursively visits the files in `dir`, directories at depth `max_depth` and deeper are skipped
+///
+/// Symlinks to files are visited like the files themselves, symlinks to directories are only
+/// followed with `follow_symlinks` and never into a directory which is already being visited
+pub fn visit_files(
+    dir: &Path,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    mut fun: impl FnMut(TraversalEvent) -> TraversalResponse,
+) {
+    let mut visitor = Visitor {
+        max_depth,
+        follow_symlinks,
+        visited: HashSet::new(),
+        fun: &mut fun,
+    };
+    if let Ok(metadata) = std::fs::metadata(dir) {
+        visitor.visited.insert((metadata.dev(), metadata.ino()));
+    }
+    visitor.visit(dir, 0);
 }
 
-fn visit_files_impl(
-    dir: &Path,
-    fun: &mut dyn FnMut(TraversalEvent) -> TraversalResponse,
-) -> TraversalResponse {
-    let iter = read_dir(dir).unwrap();
-    for element in iter {
-        let entry = match element {
-            Ok(ok) => ok,
+struct Visitor<'a> {
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    // device and inode of the directories entered so far, guards against symlink cycles
+    visited: HashSet<(u64, u64)>,
+    fun: &'a mut dyn FnMut(TraversalEvent) -> TraversalResponse,
+}
+
+impl Visitor<'_> {
+    fn visit(&mut self, dir: &Path, depth: usize) -> TraversalResponse {
+        // an unreadable directory shouldn't stop the rest of the traversal
+        let iter = match std::fs::read_dir(dir) {
+            Ok(iter) => iter,
             Err(e) => {
-                log::trace!("error listing {dir:?}: {e}");
-                continue;
+                log::warn!("skipping `{}`, it can't be listed: {e}", display(dir));
+                return TraversalResponse::Continue;
             }
         };
-        let path = entry.path();
-        match entry.metadata() {
-            Ok(ty) => {
-                if ty.is_symlink() && ty.is_dir() {
-                    // do not follow symlinks to prevent infinite loops
-                    log::trace!("{path:?} is a directory symlink, skipping")
-                } else if ty.is_dir() {
-                    match fun(TraversalEvent::EnterDirectory(&path)) {
-                        TraversalResponse::Continue => {
-                            visit_files_impl(&path, fun);
-                            if fun(TraversalEvent::LeaveDirectory) == TraversalResponse::Stop {
-                                return TraversalResponse::Stop;
-                            }
-                        }
-                        TraversalResponse::Skip => continue,
-                        TraversalResponse::Stop => return TraversalResponse::Stop,
+        for element in iter {
+            let entry = match element {
+                Ok(ok) => ok,
+                Err(e) => {
+                    log::trace!("error listing {dir:?}: {e}");
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(ok) => ok,
+                Err(e) => {
+                    report_io_error("DirEntry::metadata", &path, e);
+                    continue;
+                }
+            };
+            let ty = match metadata.is_symlink() {
+                true => match std::fs::metadata(&path) {
+                    Ok(target) if target.is_dir() && !self.follow_symlinks => {
+                        log::trace!("{path:?} is a directory symlink, skipping");
+                        continue;
                     }
-                } else if ty.is_file() {
-                    if fun(TraversalEvent::File(&path)) == TraversalResponse::Stop {
-                        return TraversalResponse::Stop;
+                    Ok(target) => target,
+                    Err(e) => {
+                        log::trace!("{path:?} is a broken symlink, skipping: {e}");
+                        continue;
                     }
+                },
+                false => metadata,
+            };
+
+            if ty.is_dir() && self.max_depth.is_some_and(|max| depth >= max) {
+                log::trace!("{path:?} exceeds the maximum depth, skipping")
+            } else if ty.is_dir() {
+                if !self.visited.insert((ty.dev(), ty.ino())) {
+                    log::trace!("{path:?} was already visited, skipping");
+                    continue;
                 }
-            }
-            Err(e) => {
-                report_io_error("DirEntry::metadata", &path, e);
+                let response = match (self.fun)(TraversalEvent::EnterDirectory(&path, depth)) {
+                    TraversalResponse::Continue => {
+                        let response = self.visit(&path, depth + 1);
+                        if response == TraversalResponse::Stop
+                            || (self.fun)(TraversalEvent::LeaveDirectory) == TraversalResponse::Stop
+                        {
+                            TraversalResponse::Stop
+                        } else {
+                            TraversalResponse::Continue
+                        }
+                    }
+                    response => response,
+                };
+                // only directories on the current path are cycles, siblings may link to the same one
+                self.visited.remove(&(ty.dev(), ty.ino()));
+                if response == TraversalResponse::Stop {
+                    return TraversalResponse::Stop;
+                }
+            } else if ty.is_file()
+                && (self.fun)(TraversalEvent::File(&path)) == TraversalResponse::Stop
+            {
+                return TraversalResponse::Stop;
             }
         }
+        TraversalResponse::Continue
     }
-    TraversalResponse::Continue
 }
 
 pub fn check_status(
@@ -201,14 +300,77 @@ pub fn check_status(
     }
 }
 
+/// Waits for the child to exit, returns `None` if it is still running after `timeout` elapses
+/// or once `abort` is set
+pub fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    abort: &AtomicBool,
+) -> std::io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if abort.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let elapsed = start.elapsed();
+        let remaining = match timeout {
+            Some(timeout) if elapsed >= timeout => return Ok(None),
+            Some(timeout) => timeout - elapsed,
+            None => Duration::MAX,
+        };
+        std::thread::sleep(remaining.min(Duration::from_millis(10)));
+    }
+}
+
 pub fn print_args(builder: &std::process::Command) {
-    let mut buf = OsString::new();
+    log::trace!("Running command `{}`", command_line(builder));
+}
+
+/// Prints the command which would have been run with --dry-run
+pub fn print_dry_run(builder: &std::process::Command) {
+    log::info!("Would run `{}`", command_line(builder));
+}
 
-    buf.push(builder.get_program());
+/// Formats the command so that it can be pasted into a shell
+pub fn command_line(builder: &std::process::Command) -> String {
+    let mut buf = shell_quote(builder.get_program());
     for a in builder.get_args() {
-        buf.push(" ");
-        buf.push(a);
+        buf.push(' ');
+        buf.push_str(&shell_quote(a));
     }
+    buf
+}
 
-    log::trace!("Running command `{}`", buf.to_string_lossy());
+/// Quotes the argument for a POSIX shell if it contains anything but plainly safe characters,
+/// arguments which aren't printable UTF8 use bash's `$'...'` quoting with escaped bytes
+fn shell_quote(arg: &OsStr) -> String {
+    let bytes = arg.as_bytes();
+    let safe = |c: u8| c.is_ascii_alphanumeric() || b"_@%+=:,./-".contains(&c);
+    if !bytes.is_empty() && bytes.iter().all(|&c| safe(c)) {
+        return arg.to_str().unwrap().to_owned();
+    }
+
+    match arg.to_str() {
+        Some(str) if !str.chars().any(char::is_control) => {
+            format!("'{}'", str.replace('\'', r"'\''"))
+        }
+        _ => {
+            let mut quoted = String::from("$'");
+            for &c in bytes {
+                match c {
+                    b'\'' | b'\\' => {
+                        quoted.push('\\');
+                        quoted.push(c as char);
+                    }
+                    b' '..=b'~' => quoted.push(c as char),
+                    _ => quoted.push_str(&format!("\\x{c:02x}")),
+                }
+            }
+            quoted.push('\'');
+            quoted
+        }
+    }
 }
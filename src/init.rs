@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use crate::fs::{self, AlreadyReported};
+
+const MAIN_SOURCE: &str = r#"#include <iostream>
+
+int main() {
+    int a, b;
+    if (!(std::cin >> a >> b)) {
+        std::cout << "Invalid input." << std::endl;
+        return 0;
+    }
+    std::cout << "Sum: " << a + b << std::endl;
+    return 0;
+}
+"#;
+
+// the first line is the separator, each test is a `<name> in` section followed by `<name> out`
+const MAIN_SAMPLES: &str = r#"---
+sum in
+---
+1 2
+---
+sum out
+---
+Sum: 3
+---
+negative in
+---
+-5 3
+---
+negative out
+---
+Sum: -2
+---
+invalid in
+---
+abc
+---
+invalid out
+---
+Invalid input.
+"#;
+
+/// Creates the directory with an example source file and its samples
+pub fn subcommand_init(dir: &Path, force: bool) -> fs::Result<()> {
+    let files = [
+        (dir.join("main.cpp"), MAIN_SOURCE),
+        (dir.join("main.samples"), MAIN_SAMPLES),
+    ];
+
+    if !force {
+        let existing = files.iter().filter(|(path, _)| path.exists());
+        let mut any = false;
+        for (path, _) in existing {
+            log::error!("`{}` already exists", fs::display(path));
+            any = true;
+        }
+        if any {
+            log::info!("use --force to overwrite existing files");
+            return Err(AlreadyReported);
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    for (path, contents) in &files {
+        log::debug!("writing `{}`", fs::display(path));
+        fs::write(path, contents.as_bytes())?;
+    }
+
+    log::info!(
+        "initialized `{}`, try `testprog test main.cpp` inside it",
+        fs::display(dir)
+    );
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C handler which lets the running builds and tests finish, so that the cache
+/// is still saved, a second Ctrl-C exits immediately
+pub fn install() {
+    let result = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        log::warn!("Interrupted, waiting for running processes, press Ctrl-C again to exit now");
+    });
+    if let Err(e) = result {
+        log::debug!("failed to install the Ctrl-C handler: {e}");
+    }
+}
+
+/// Whether Ctrl-C was pressed, no new work should be started
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
@@ -1,7 +1,10 @@
 #![allow(dead_code)]
 
+use std::{io::Write, time::Instant};
+
 use log::{Metadata, Record};
 use nu_ansi_term::Color;
+use serde::Serialize;
 
 #[derive(Clone, Debug)]
 pub struct CustomLogger {
@@ -9,6 +12,10 @@ pub struct CustomLogger {
     pub color: bool,
     pub print_level: bool,
     pub print_file: bool,
+    pub timestamps: bool,
+    // print records as JSON lines, color, level and file are ignored
+    pub json: bool,
+    pub start: Instant,
 }
 
 impl CustomLogger {
@@ -24,6 +31,10 @@ impl CustomLogger {
         self.print_file = print_file;
         self
     }
+    pub fn timestamps(&mut self, timestamps: bool) -> &mut CustomLogger {
+        self.timestamps = self.timestamps || timestamps;
+        self
+    }
     pub fn color(&mut self, color: bool) -> &mut CustomLogger {
         self.color = color;
         self
@@ -34,6 +45,17 @@ impl CustomLogger {
     }
 }
 
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    level: String,
+    target: &'a str,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed: Option<f64>,
+}
+
 impl log::Log for CustomLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= self.max_level
@@ -44,6 +66,27 @@ impl log::Log for CustomLogger {
             return;
         }
 
+        crate::progress::suspend(|| self.write_record(record));
+    }
+    fn flush(&self) {}
+}
+
+impl CustomLogger {
+    fn write_record(&self, record: &Record) {
+        if self.json {
+            let json = JsonRecord {
+                level: record.level().as_str().to_ascii_lowercase(),
+                target: record.target(),
+                file: record.file(),
+                line: record.line(),
+                message: record.args().to_string(),
+                elapsed: self.timestamps.then(|| self.start.elapsed().as_secs_f64()),
+            };
+            let json = serde_json::to_string(&json).unwrap();
+            _ = writeln!(std::io::stderr().lock(), "{json}");
+            return;
+        }
+
         let (color, level) = match record.level() {
             log::Level::Error => (Color::Red, "error"),
             log::Level::Warn => (Color::Yellow, "warn"),
@@ -52,12 +95,24 @@ impl log::Log for CustomLogger {
             log::Level::Trace => (Color::Magenta, "trace"),
         };
 
+        // the record is written under a single lock so that lines from other threads don't interleave
+        let mut stderr = std::io::stderr().lock();
+        if self.timestamps {
+            let elapsed = self.start.elapsed().as_secs_f64();
+            if self.color {
+                let gray = Color::LightGray;
+                _ = write!(stderr, "{}{elapsed:9.3}s{} ", gray.prefix(), gray.suffix());
+            } else {
+                _ = write!(stderr, "{elapsed:9.3}s ")
+            }
+        }
+
         if self.print_level && !level.is_empty() {
             let (pre, post) = (color.prefix(), color.suffix());
             if self.color {
-                eprint!("{pre}{level:5}{post} ");
+                _ = write!(stderr, "{pre}{level:5}{post} ");
             } else {
-                eprint!("{level:5} ")
+                _ = write!(stderr, "{level:5} ")
             }
         }
 
@@ -65,25 +120,32 @@ impl log::Log for CustomLogger {
             if let (Some(file), Some(line)) = (record.file(), record.line()) {
                 if self.color {
                     let gray = Color::LightGray;
-                    eprint!("{}{file}:{line}{} ", gray.prefix(), gray.suffix());
+                    _ = write!(stderr, "{}{file}:{line}{} ", gray.prefix(), gray.suffix());
                 } else {
-                    eprint!("{file}:{line} ")
+                    _ = write!(stderr, "{file}:{line} ")
                 }
             }
         }
 
-        eprintln!("{}", record.args());
+        _ = writeln!(stderr, "{}", record.args());
     }
-    fn flush(&self) {}
+}
+
+/// Whether color was disabled through a non-empty `NO_COLOR` variable, see https://no-color.org
+pub fn no_color_requested(no_color: Option<&std::ffi::OsStr>) -> bool {
+    no_color.is_some_and(|v| !v.is_empty())
 }
 
 pub fn make_logger_from_env() -> CustomLogger {
     use std::io::IsTerminal as _;
-    let env = std::env::var("RUST_LOG").unwrap_or(String::new());
+    let env = std::env::var("RUST_LOG").unwrap_or_default();
 
-    let mut color = std::io::stderr().is_terminal();
+    let mut color = std::io::stderr().is_terminal()
+        && !no_color_requested(std::env::var_os("NO_COLOR").as_deref());
     let mut print_level = false;
     let mut print_file = false;
+    let mut timestamps = false;
+    let mut json = false;
     let mut max_level = log::LevelFilter::Error;
     for str in env.split(',') {
         match str.trim() {
@@ -95,6 +157,8 @@ pub fn make_logger_from_env() -> CustomLogger {
             "color" => color = true,
             "level" => print_level = true,
             "file" => print_file = true,
+            "time" => timestamps = true,
+            "json" => json = true,
             _ => {}
         }
     }
@@ -104,5 +168,22 @@ pub fn make_logger_from_env() -> CustomLogger {
         color,
         print_level,
         print_file,
+        timestamps,
+        json,
+        start: Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_disables_color() {
+        assert!(no_color_requested(Some("1".as_ref())));
+        assert!(no_color_requested(Some("false".as_ref())));
+        assert!(!no_color_requested(None));
+        // an empty value doesn't count
+        assert!(!no_color_requested(Some("".as_ref())));
     }
 }
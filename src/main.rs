@@ -1,23 +1,54 @@
+mod bench;
 mod cli;
+mod compare;
+mod config;
 mod database;
+mod diff;
 mod fs;
+mod init;
+mod interrupt;
 mod logger;
+mod progress;
+mod report;
 mod samples;
+mod watch;
 
 use bstr::ByteSlice;
 use clap::{ColorChoice, Parser};
-use cli::{Arguments, Command};
-use database::CacheEntry;
+use cli::{Arguments, BuildOpts, Command, ConvertOpts, SampleOpts, TestOpts};
+use database::{BuildStats, CacheEntry};
 use fs::{check_status, print_args, AlreadyReported};
-use nu_ansi_term::Color;
+use nu_ansi_term::{Color, Style};
+use progress::Progress;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use std::{
-    ffi::OsString, io::Write, os::unix::prelude::OsStrExt, path::Path, process::ChildStdin, rc::Rc,
+    collections::HashSet,
+    ffi::OsString,
+    io::{IsTerminal, Write},
+    os::unix::prelude::OsStrExt,
+    path::{Component, Path, PathBuf},
+    process::ChildStdin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use crate::{database::Database, samples::subcommand_convert};
+use crate::{
+    database::Database,
+    samples::{subcommand_convert, ArchiveFormat},
+};
 
 #[macro_export]
 macro_rules! bail {
+    // `bail!(Build, "...")` fails with a specific kind of `fs::Error`
+    ($kind:ident, $($a:tt)*) => {{
+        log::error!($($a)*);
+        return Err($crate::fs::Error::$kind);
+    }};
     ($($a:tt)*) => {{
         log::error!($($a)*);
         return Err(AlreadyReported);
@@ -26,6 +57,9 @@ macro_rules! bail {
 
 fn main() {
     let result = main_();
+    if interrupt::interrupted() {
+        std::process::exit(130);
+    }
     if result.is_err() {
         std::process::exit(1);
     }
@@ -34,49 +68,130 @@ fn main() {
 fn init() -> Arguments {
     let mut args = Arguments::parse();
 
-    let level = match (args.quiet, args.verbose) {
+    // stdout only has the paths, the build shouldn't bury them in messages on the terminal
+    let print_binaries = matches!(
+        args.command,
+        Command::Build {
+            print_binaries: true,
+            ..
+        }
+    );
+    let quiet = args.quiet || (print_binaries && args.verbose == 0);
+    let level = match (quiet, args.verbose) {
         (true, _) => log::LevelFilter::Error,
         (false, 0) => log::LevelFilter::Info,
         (false, 1) => log::LevelFilter::Debug,
         (false, _) => log::LevelFilter::Trace,
     };
 
+    // only decides the color of our own output, children get `--child-color`
     if let clap::ColorChoice::Auto = args.color {
-        let supported = anstyle_query::term_supports_ansi_color();
+        let supported = anstyle_query::term_supports_ansi_color()
+            && !logger::no_color_requested(std::env::var_os("NO_COLOR").as_deref());
         args.color = match supported {
             true => clap::ColorChoice::Always,
             false => clap::ColorChoice::Never,
         };
     };
 
-    logger::make_logger_from_env()
+    let mut logger = logger::make_logger_from_env();
+    logger
         .max_level(level)
         .print_level(true)
+        .timestamps(args.timestamps)
         .color(matches!(args.color, clap::ColorChoice::Always))
         .install();
+
+    // the bar redraws itself with escape codes, it would garble redirected output
+    progress::enable(
+        std::io::stderr().is_terminal()
+            && logger.color
+            && !logger.json
+            && level == log::LevelFilter::Info
+            && !args.dry_run,
+    );
+
+    args.ask = interactivity(args.ask, args.no_prompt, std::io::stdin().is_terminal());
     args
 }
 
-fn main_() -> Result<(), AlreadyReported> {
-    let args = init();
+/// Resolves --ask and --no-prompt, prompting is turned off when stdin isn't a terminal
+fn interactivity(
+    ask: cli::Interactivity,
+    no_prompt: bool,
+    stdin_is_terminal: bool,
+) -> cli::Interactivity {
+    if no_prompt {
+        return cli::Interactivity::No;
+    }
+    // nobody could answer the prompt, in CI it would wait forever
+    if ask == cli::Interactivity::Yes && !stdin_is_terminal {
+        log::debug!("stdin isn't a terminal, failed tests won't prompt");
+        return cli::Interactivity::Skip;
+    }
+    ask
+}
+
+fn main_() -> fs::Result<()> {
+    let mut args = init();
+
+    if let Some(options) = args.command.get_build_options_mut() {
+        if let Some(config) = config::Config::load(&args.root)? {
+            config.apply(options);
+        }
+        options.targets = expand_globs(&args.root, &options.targets);
+        options.targets = dedup_targets(std::mem::take(&mut options.targets));
+    }
 
     log::trace!("{args:#?}");
 
-    let out_dir = args.root.join("out");
+    fs::set_display_root(&args.root);
+
+    // only commands which build and test can stop early, others are quick
+    if args.command.get_build_options().is_some() {
+        interrupt::install();
+    }
+
+    let out_dir = args
+        .root
+        .join(args.output_dir.as_deref().unwrap_or(Path::new("out")));
     let cache_file = out_dir.join("cache.json");
 
+    if let Command::Test {
+        build_options,
+        test_options,
+    } = &mut args.command
+    {
+        if test_options.discover {
+            build_options.targets = discover_targets(&args.root, &out_dir);
+        }
+    }
+
     match &args.command {
-        Command::Run { build_options } => {
-            if build_options.targets.len() != 1 {
-                bail!("The 'run' subcommand expects a single target");
-            }
+        Command::Run { build_options, .. } if build_options.targets.len() != 1 => {
+            bail!("The 'run' subcommand expects a single target");
+        }
+        Command::Bench { build_options, .. } if build_options.targets.len() != 1 => {
+            bail!("The 'bench' subcommand expects a single target");
         }
-        Command::Clean => {
+        Command::Clean { targets } if targets.is_empty() => {
+            check_out_dir(&out_dir)?;
             return fs::remove_dir_all(&out_dir);
         }
+        Command::Init { dir, force } => {
+            let dir = match dir {
+                Some(dir) => args.root.join(dir),
+                None => args.root.clone(),
+            };
+            return init::subcommand_init(&dir, *force);
+        }
+        Command::Check { file, separator } => {
+            return samples::subcommand_check(&args.root.join(file), separator.as_deref());
+        }
         _ => {}
     };
 
+    check_out_dir(&out_dir)?;
     if !out_dir.exists() {
         fs::create_dir_all(&out_dir)?;
     }
@@ -93,69 +208,363 @@ fn main_() -> Result<(), AlreadyReported> {
                 log::info!("No targets provided");
                 return Ok(());
             }
-
-            let mut errors = false;
-            let binaries = options
-                .targets
-                .iter()
-                .filter_map(|file| {
-                    cache
-                        .build_file(&file, options)
-                        .map_err(|_| {
-                            errors = true;
-                            ()
-                        })
-                        .ok()
-                })
-                .collect();
-
-            if !args.no_cache {
-                _ = cache.save_to_file();
+            match &args.command {
+                Command::Test { test_options, .. } if test_options.list => {
+                    cache.entries(&options.targets)
+                }
+                _ => {
+                    let binaries = build_targets(&args, options, &mut cache);
+                    if args.stats {
+                        let BuildStats {
+                            rebuilt,
+                            cached,
+                            failed,
+                        } = cache.stats;
+                        let files = rebuilt + cached;
+                        match failed {
+                            0 => log::info!("{files} files, {rebuilt} rebuilt, {cached} cached"),
+                            _ => log::info!(
+                                "{files} files, {rebuilt} rebuilt, {cached} cached, {failed} failed to build"
+                            ),
+                        }
+                    }
+                    binaries
+                }
             }
-            binaries
         }
         _ => vec![],
     };
 
     match &args.command {
-        Command::Build { .. } => {}
-        Command::With { with, .. } => subcommand_with(&binaries, with),
-        Command::Run { .. } => {
-            let entry = binaries.first().unwrap();
-            log::info!("Running {}", entry.source.display());
-            exec(&mut std::process::Command::new(&entry.binary))?;
-        }
-        Command::Test { diff, .. } => subcomand_test(&binaries, &out_dir, &args, diff.as_deref()),
-        Command::Convert {
-            archive,
-            output,
-            sample_subdirs,
+        Command::Build {
+            build_options,
+            print_binaries,
         } => {
+            if *print_binaries {
+                let mut stdout = std::io::stdout().lock();
+                for entry in &binaries {
+                    _ = writeln!(stdout, "{}", entry.binary.display());
+                }
+            }
+            check_built(&binaries, build_options)?;
+        }
+        Command::With {
+            build_options,
+            with,
+            each,
+        } => {
+            check_built(&binaries, build_options)?;
+            subcommand_with(&binaries, with, *each, &args)?;
+        }
+        Command::Run {
+            args: run_args,
+            env,
+            stdin,
+            ..
+        } => {
+            // the build errors are already reported
+            let Some(entry) = binaries.first() else {
+                return Err(AlreadyReported);
+            };
+            let mut command = std::process::Command::new(&entry.binary);
+            if let Some(stdin) = stdin {
+                // the file stays connected to stdin across the exec
+                command.stdin(fs::open(&args.root.join(stdin))?);
+            }
+            log::info!("Running {}", fs::display(&entry.source));
+            env.apply(&mut command);
+            args.apply_child_color(&mut command);
+            exec(command.args(run_args), args.dry_run)?;
+        }
+        Command::Test {
+            build_options,
+            test_options,
+        } => {
+            let result = subcomand_test(&binaries, &out_dir, &args, test_options);
+            if test_options.watch {
+                watch_test(&mut cache, &out_dir, &args, build_options, test_options)?;
+            }
+            result?;
+            // the tests of targets which didn't build never ran, that isn't a pass
+            check_built(&binaries, build_options)?;
+        }
+        Command::Bench {
+            sample_options,
+            bench_options,
+            ..
+        } => {
+            // the build errors are already reported
+            let Some(entry) = binaries.first() else {
+                return Err(AlreadyReported);
+            };
+            bench::subcommand_bench(entry, &out_dir, &args, sample_options, bench_options)?;
+        }
+        Command::Convert { convert_options } => {
+            let ConvertOpts {
+                archive, output, ..
+            } = convert_options;
             let output = output.clone().unwrap_or_else(|| {
                 let mut path = archive.clone();
                 if let Some(str) = archive.file_name().unwrap().to_str() {
-                    let found = [".gz", ".tgz", ".tar.gz"]
-                        .iter()
-                        .flat_map(|ext| str.strip_suffix(*ext))
-                        .next();
-                    if let Some(found) = found {
-                        path = archive.with_file_name(found);
+                    if let Some((_, stem)) = ArchiveFormat::from_file_name(str) {
+                        path = archive.with_file_name(stem);
                     }
                 }
                 path.with_extension("samples")
             });
 
-            subcommand_convert(&out_dir, &archive, &output, &args, &sample_subdirs)?;
+            subcommand_convert(&out_dir, &output, &args, convert_options)?;
+        }
+        Command::Clean { targets } => {
+            clean_targets(&mut cache, targets, &out_dir.join("diff"));
+            if !args.no_cache {
+                cache.save_to_file()?;
+            }
+        }
+        Command::Init { .. } | Command::Check { .. } => {
+            unreachable!("handled before the output directory is created")
+        }
+    }
+
+    Ok(())
+}
+
+/// Expands targets containing glob patterns relative to the root, other targets are kept as is
+fn expand_globs(root: &Path, targets: &[PathBuf]) -> Vec<PathBuf> {
+    let mut expanded = Vec::new();
+    for target in targets {
+        let is_glob = target.to_str().is_some_and(|s| s.contains(['*', '?', '[']));
+        if !is_glob {
+            expanded.push(target.clone());
+            continue;
+        }
+
+        let pattern = root.join(target);
+        let paths = match glob::glob(&pattern.to_string_lossy()) {
+            Ok(paths) => paths,
+            Err(e) => {
+                log::warn!("invalid glob pattern `{}`: {e}", fs::display(target));
+                continue;
+            }
+        };
+
+        let before = expanded.len();
+        for path in paths {
+            match path {
+                Ok(path) => {
+                    let relative = path.strip_prefix(root).unwrap_or(&path);
+                    expanded.push(relative.to_owned());
+                }
+                Err(e) => log::debug!("error while expanding `{}`: {e}", fs::display(target)),
+            }
+        }
+        if expanded.len() == before {
+            log::warn!("pattern `{}` didn't match any files", fs::display(target));
+        }
+    }
+    expanded
+}
+
+/// Removes targets which refer to the same file as an earlier one, keeping the order
+fn dedup_targets(targets: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    targets
+        .into_iter()
+        .filter(|target| {
+            // missing files are reported by the build, compare them as written
+            let canonical = std::fs::canonicalize(target).unwrap_or_else(|_| target.clone());
+            let first = seen.insert(canonical);
+            if !first {
+                log::debug!("skipping `{}`, it was already given", fs::display(target));
+            }
+            first
+        })
+        .collect()
+}
+
+/// Finds the C/C++ sources under the root which have a samples file, relative to the root
+fn discover_targets(root: &Path, out_dir: &Path) -> Vec<PathBuf> {
+    const MAX_DISCOVER_DEPTH: usize = 8;
+    // the outputs of other build tools, large and without samples
+    const SKIPPED_DISCOVER_DIRS: [&str; 2] = ["target", "node_modules"];
+
+    let mut targets = Vec::new();
+    fs::visit_files(root, Some(MAX_DISCOVER_DEPTH), false, |event| {
+        match event {
+            fs::TraversalEvent::EnterDirectory(dir, _) => {
+                let name = dir.file_name().unwrap_or_default();
+                let hidden = name.as_bytes().starts_with(b".");
+                if hidden || SKIPPED_DISCOVER_DIRS.iter().any(|s| name == *s) || dir == out_dir {
+                    log::trace!("not discovering in `{}`", fs::display(dir));
+                    return fs::TraversalResponse::Skip;
+                }
+            }
+            fs::TraversalEvent::LeaveDirectory => {}
+            fs::TraversalEvent::File(file) => {
+                let extension = file.extension().unwrap_or_default().as_bytes();
+                if matches!(extension, b"c" | b"cpp") && !samples::samples_files(file).is_empty() {
+                    let relative = file.strip_prefix(root).unwrap_or(file);
+                    targets.push(relative.to_owned());
+                }
+            }
+        }
+        fs::TraversalResponse::Continue
+    });
+    targets.sort();
+
+    match targets.is_empty() {
+        true => log::warn!("Discovered no sources with a samples file"),
+        false => log::info!("Discovered {} targets with a samples file", targets.len()),
+    }
+    targets
+}
+
+fn build_targets(
+    args: &Arguments,
+    options: &BuildOpts,
+    cache: &mut Database,
+) -> Vec<Rc<CacheEntry>> {
+    let results = cache.build_files(&options.targets, options, args.jobs, args.dry_run);
+    cache.stats.failed += results
+        .iter()
+        .filter(|r| matches!(r, Err(fs::Error::Build)))
+        .count();
+    let binaries = results.into_iter().filter_map(Result::ok).collect();
+
+    if !args.no_cache && !args.dry_run {
+        _ = cache.save_to_file();
+    }
+    binaries
+}
+
+/// Fails if some of the targets didn't produce a binary, their errors are already reported
+fn check_built(binaries: &[Rc<CacheEntry>], options: &BuildOpts) -> fs::Result<()> {
+    let failed = options.targets.len() - binaries.len();
+    if failed > 0 {
+        bail!(
+            Build,
+            "{failed} of {} targets failed to build",
+            options.targets.len()
+        );
+    }
+    Ok(())
+}
+
+/// Rebuilds and retests the targets whenever their inputs change, only returns on error
+fn watch_test(
+    cache: &mut Database,
+    out_dir: &Path,
+    args: &Arguments,
+    build_options: &BuildOpts,
+    test_options: &TestOpts,
+) -> fs::Result<()> {
+    loop {
+        watch::wait_for_change(&cache.input_files(&build_options.targets))?;
+        if interrupt::interrupted() {
+            return Err(AlreadyReported);
+        }
+
+        let separator = style(args, Color::DarkGray).paint("-".repeat(40));
+        eprintln!("\n{separator}\n");
+
+        let binaries = build_targets(args, build_options, cache);
+        _ = subcomand_test(&binaries, out_dir, args, test_options);
+    }
+}
+
+fn clean_targets(cache: &mut Database, targets: &[PathBuf], diff_dir: &Path) {
+    for target in targets {
+        let Some(entry) = cache.remove(target) else {
+            log::warn!("`{}` isn't in the cache", fs::display(target));
+            continue;
+        };
+        log::info!("Cleaning {}", fs::display(&entry.source));
+
+        let prefix = diff_file_prefix(diff_dir, &entry.source);
+        let Ok(entries) = std::fs::read_dir(prefix.parent().unwrap()) else {
+            continue;
+        };
+        let prefix = prefix.file_name().unwrap().as_bytes();
+        for diff_file in entries.flatten() {
+            if !diff_file.file_name().as_bytes().starts_with(prefix) {
+                continue;
+            }
+            // tests from `<source stem>.<group>.samples` are named `<group>/<name>`
+            match diff_file.file_type().is_ok_and(|t| t.is_dir()) {
+                true => _ = fs::remove_dir_all(&diff_file.path()),
+                false => _ = fs::remove_file(&diff_file.path()),
+            }
         }
-        Command::Clean => unreachable!(),
+    }
+}
+
+/// Fails if the output directory is occupied by something else than a directory
+fn check_out_dir(out_dir: &Path) -> fs::Result<()> {
+    match std::fs::metadata(out_dir) {
+        Ok(metadata) if !metadata.is_dir() => bail!(
+            "The output directory `{}` is a file, remove or rename it, or pick a different directory with --output-dir",
+            fs::display(out_dir)
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn subcommand_with(
+    entry_paths: &[Rc<CacheEntry>],
+    arguments: &[OsString],
+    each: bool,
+    args: &Arguments,
+) -> fs::Result<()> {
+    if arguments.is_empty() {
+        bail!("The 'with' subcommand expects a command to run");
     }
 
+    if !each {
+        let artifacts = entry_paths.iter().map(|p| p.binary.as_os_str().to_owned());
+        let arguments = substitute_binaries(arguments, artifacts);
+        let mut command = std::process::Command::new(&arguments[0]);
+        args.apply_child_color(&mut command);
+        return exec(command.args(&arguments[1..]), args.dry_run);
+    }
+
+    let mut failed = 0;
+    for entry in entry_paths {
+        if interrupt::interrupted() {
+            return Err(AlreadyReported);
+        }
+        let artifact = entry.binary.as_os_str().to_owned();
+        let arguments = substitute_binaries(arguments, std::iter::once(artifact));
+        let mut command = std::process::Command::new(&arguments[0]);
+        command.args(&arguments[1..]);
+        args.apply_child_color(&mut command);
+        if args.dry_run {
+            fs::print_dry_run(&command);
+            continue;
+        }
+
+        print_args(&command);
+        let program = arguments[0].to_string_lossy();
+        if check_status(&program, command.status()).is_err() {
+            log::error!("`{program}` failed for {}", fs::display(&entry.source));
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "`with` failed for {failed} of {} binaries",
+            entry_paths.len()
+        );
+    }
     Ok(())
 }
 
-fn subcommand_with(entry_paths: &[Rc<CacheEntry>], arguments: &[OsString]) {
-    let artifacts = entry_paths.iter().map(|p| p.binary.as_os_str().to_owned());
+/// Replaces every `{bin}` argument with the binaries, they are appended if there is no placeholder
+fn substitute_binaries(
+    arguments: &[OsString],
+    artifacts: impl Iterator<Item = OsString> + Clone,
+) -> Vec<OsString> {
     let mut arguments = arguments.to_owned();
+    let count = artifacts.clone().count();
 
     let mut bin_subsituted = false;
     let mut i = 0;
@@ -163,7 +572,7 @@ fn subcommand_with(entry_paths: &[Rc<CacheEntry>], arguments: &[OsString]) {
         let current = &arguments[i];
         if let b"{bin}" = current.as_bytes() {
             arguments.splice(i..=i, artifacts.clone());
-            i += entry_paths.len();
+            i += count;
             bin_subsituted = true;
         } else {
             i += 1;
@@ -172,11 +581,14 @@ fn subcommand_with(entry_paths: &[Rc<CacheEntry>], arguments: &[OsString]) {
     if !bin_subsituted {
         arguments.extend(artifacts);
     }
-
-    _ = exec(std::process::Command::new(&arguments[0]).args(&arguments[1..]));
+    arguments
 }
 
-fn exec(command: &mut std::process::Command) -> fs::Result<()> {
+fn exec(command: &mut std::process::Command, dry_run: bool) -> fs::Result<()> {
+    if dry_run {
+        fs::print_dry_run(command);
+        return Ok(());
+    }
     print_args(command);
 
     use std::os::unix::process::CommandExt;
@@ -188,201 +600,1135 @@ fn exec(command: &mut std::process::Command) -> fs::Result<()> {
     check_status("child", status)
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TestStatus {
+    Passed,
+    Failed,
+    Timeout,
+    Crashed,
+    MemoryLimit,
+    OutputLimit,
+    Flaky,
+}
+
+impl TestStatus {
+    const ALL: [TestStatus; 7] = [
+        TestStatus::Passed,
+        TestStatus::Failed,
+        TestStatus::Timeout,
+        TestStatus::Crashed,
+        TestStatus::MemoryLimit,
+        TestStatus::OutputLimit,
+        TestStatus::Flaky,
+    ];
+
+    fn label(self) -> (&'static str, Color) {
+        match self {
+            TestStatus::Passed => ("Ok", Color::LightGreen),
+            TestStatus::Failed => ("Err", Color::LightRed),
+            TestStatus::Timeout => ("Timeout", Color::LightYellow),
+            TestStatus::Crashed => ("Crashed", Color::LightRed),
+            TestStatus::MemoryLimit => ("Memory limit", Color::LightMagenta),
+            TestStatus::OutputLimit => ("Output too large", Color::LightMagenta),
+            TestStatus::Flaky => ("Flaky", Color::LightYellow),
+        }
+    }
+    /// The status as it appears in reports
+    fn id(self) -> &'static str {
+        match self {
+            TestStatus::Passed => "ok",
+            TestStatus::Failed => "fail",
+            TestStatus::Timeout => "timeout",
+            TestStatus::Crashed => "crash",
+            TestStatus::MemoryLimit => "memory_limit",
+            TestStatus::OutputLimit => "output_limit",
+            TestStatus::Flaky => "flaky",
+        }
+    }
+    /// Describes the tests with this status in the summary
+    fn summary(self) -> &'static str {
+        match self {
+            TestStatus::Passed => "passed",
+            TestStatus::Failed => "failed",
+            TestStatus::Timeout => "timed out",
+            TestStatus::Crashed => "crashed",
+            TestStatus::MemoryLimit => "exceeded the memory limit",
+            TestStatus::OutputLimit => "printed too much",
+            TestStatus::Flaky => "flaky",
+        }
+    }
+}
+
+struct TestResult {
+    source: PathBuf,
+    name: String,
+    status: TestStatus,
+    duration: Duration,
+    // why the test didn't pass
+    message: Option<String>,
+}
+
+#[derive(Default)]
+struct TestSummary {
+    results: Vec<TestResult>,
+    filtered: usize,
+}
+
+impl TestSummary {
+    fn count(&self, status: TestStatus) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+    fn all_passed(&self) -> bool {
+        self.results.iter().all(|r| r.status == TestStatus::Passed)
+    }
+}
+
+/// Returns a style with the color, or a plain style if color output is disabled
+fn style(args: &Arguments, color: Color) -> Style {
+    match args.color == ColorChoice::Never {
+        true => Style::new(),
+        false => color.normal(),
+    }
+}
+
+struct TestContext<'a> {
+    args: &'a Arguments,
+    options: &'a TestOpts,
+    diff_dir: PathBuf,
+    // the seed of the --shuffle order
+    shuffle_seed: Option<u64>,
+    // the tests to run with --failed, all of them if there are no saved results
+    failed: Option<&'a HashSet<(PathBuf, String)>>,
+    // held while printing multiple lines or prompting so that parallel tests don't interleave
+    console: &'a Mutex<()>,
+    // the diff file prefixes of failed tests, diffed at the end with --diff-batch
+    batch_diffs: &'a Mutex<Vec<PathBuf>>,
+    // the directory from --sample-dir and the samples collected from it, used for every binary
+    dir_samples: Option<(&'a Path, &'a [u8])>,
+}
+
+impl TestContext<'_> {
+    fn run(&self) -> RunContext<'_> {
+        RunContext {
+            args: self.args,
+            options: &self.options.sample_options,
+            diff_dir: &self.diff_dir,
+        }
+    }
+}
+
+/// What running a binary on a single test needs, `bench` runs tests without a `TestContext`
+struct RunContext<'a> {
+    args: &'a Arguments,
+    options: &'a SampleOpts,
+    diff_dir: &'a Path,
+}
+
+/// The input of a test to be written to the stdin of its child by a writer thread
+struct StdinWrite {
+    input: Box<[u8]>,
+    stdin: ChildStdin,
+    source: PathBuf,
+    name: String,
+}
+
 fn subcomand_test(
     entry_paths: &[Rc<CacheEntry>],
     out_dir: &Path,
     args: &cli::Arguments,
-    diff_command: Option<&str>,
-) {
-    let (w_sender, w_receiver) = std::sync::mpsc::channel::<(Box<[u8]>, ChildStdin)>();
-    let join = std::thread::spawn(move || {
-        while let Ok((file, mut stdin)) = w_receiver.recv() {
-            if let Err(e) = stdin.write_all(&file) {
-                _ = fs::report_custom("writing to child stdin failed", e);
-            }
+    options: &TestOpts,
+) -> fs::Result<()> {
+    // prompting needs the terminal to itself
+    let jobs = match args.ask {
+        cli::Interactivity::Yes => 1,
+        _ => args.jobs.get().min(entry_paths.len()),
+    };
+    let console = Mutex::new(());
+    let batch_diffs = Mutex::new(Vec::new());
+    let diff_dir = diff_dir(out_dir, &options.sample_options);
+    let last_results = out_dir.join(report::LAST_RESULTS_FILE);
+    let failed = match options.failed {
+        true if last_results.is_file() => Some(report::read_failed(&last_results)?),
+        true => {
+            log::warn!("There are no results of a previous run, running all tests");
+            None
+        }
+        false => None,
+    };
+    let dir_samples = load_dir_samples(args, &options.sample_options)?;
+    let shuffle_seed = match options.shuffle {
+        true => {
+            let seed = options.seed.unwrap_or_else(rand::random);
+            log::info!("Shuffling tests with --seed {seed}");
+            Some(seed)
+        }
+        false => None,
+    };
+
+    let queue = entry_paths
+        .iter()
+        .map(|paths| CacheEntry::clone(paths))
+        .enumerate()
+        .collect::<Vec<_>>();
+    let queue = Mutex::new(queue.into_iter());
+    let summaries = Mutex::new(Vec::new());
+    let passing_level = passing_level(options);
+    let progress = match args.dry_run || options.list {
+        true => Progress::start(0, "tested"),
+        false => Progress::start(entry_paths.len(), "tested"),
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                let context = TestContext {
+                    args,
+                    options,
+                    diff_dir: diff_dir.clone(),
+                    shuffle_seed,
+                    failed: failed.as_ref(),
+                    console: &console,
+                    batch_diffs: &batch_diffs,
+                    dir_samples: dir_samples.as_ref().map(|(dir, c)| (*dir, &c[..])),
+                };
+                loop {
+                    if interrupt::interrupted() {
+                        break;
+                    }
+                    let Some((index, paths)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    progress.set_message(fs::display(&paths.source).to_string());
+                    log::log!(passing_level, "Testing {}", fs::display(&paths.source));
+                    let mut summary = TestSummary::default();
+                    test_binary(&context, &paths, &mut summary);
+                    progress.inc();
+                    summaries.lock().unwrap().push((index, summary));
+                }
+            });
         }
     });
 
-    for paths in entry_paths {
-        log::info!("Testing {}", paths.source.display());
-        test_binary(paths, out_dir, args, &w_sender, diff_command);
+    drop(progress);
+
+    // the summaries are merged in the order of the targets regardless of which finished first
+    let mut summaries = summaries.into_inner().unwrap();
+    summaries.sort_by_key(|(index, _)| *index);
+    let mut summary = TestSummary::default();
+    for (_, binary_summary) in summaries {
+        summary.results.extend(binary_summary.results);
+        summary.filtered += binary_summary.filtered;
     }
 
-    drop(w_sender);
-    _ = join.join();
+    if args.dry_run || options.list {
+        return Ok(());
+    }
+
+    let mut line = format!("{} passed", summary.count(TestStatus::Passed));
+    for status in &TestStatus::ALL[1..] {
+        let count = summary.count(*status);
+        if count > 0 {
+            let text = format!("{count} {}", status.summary());
+            line = format!("{line}, {}", style(args, status.label().1).paint(text));
+        }
+    }
+    if summary.filtered > 0 {
+        line = format!("{line}, {} skipped", summary.filtered);
+    }
+    if let Some(tag) = &options.tag {
+        line = format!("[{tag}] {line}");
+    }
+    log::info!("{line}");
+
+    let tag = options.tag.as_deref();
+    _ = report::update_last_results(&last_results, &summary.results, tag);
+
+    if let Some(slowest) = options.slowest.filter(|n| *n > 0) {
+        let mut results = summary.results.iter().collect::<Vec<_>>();
+        results.sort_by_key(|r| std::cmp::Reverse(r.duration));
+        log::info!("Slowest tests:");
+        for result in results.iter().take(slowest) {
+            let seconds = result.duration.as_secs_f64();
+            log::info!(
+                "  {seconds:.2}s {} {}",
+                fs::display(&result.source),
+                result.name
+            );
+        }
+    }
+
+    let mut batch_diffs = batch_diffs.into_inner().unwrap();
+    if let (Some(diff), false) = (&options.diff, batch_diffs.is_empty()) {
+        batch_diffs.sort();
+        diff_batch(diff, &batch_diffs, args);
+    }
+
+    if let Some(path) = &options.report {
+        report::write_json(path, &summary.results, tag)?;
+    }
+    if let Some(path) = &options.junit {
+        report::write_junit(path, &summary.results, tag)?;
+    }
+
+    match summary.all_passed() {
+        true => Ok(()),
+        false => Err(fs::Error::TestsFailed),
+    }
 }
 
-fn test_binary(
-    paths: &CacheEntry,
-    out_dir: &Path,
+/// The level of messages about passing tests, they are hidden by --quiet-passing
+fn passing_level(options: &TestOpts) -> log::Level {
+    match options.quiet_passing {
+        true => log::Level::Debug,
+        false => log::Level::Info,
+    }
+}
+
+/// Spawns a thread writing the input of a test to the stdin of its child, the pipe is closed
+/// once it is written
+///
+/// Every child gets its own writer so that its input is written while its output is read,
+/// a child which doesn't read its input can't hold up the other tests
+fn spawn_writer(mut write: StdinWrite) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        if let Err(e) = write.stdin.write_all(&write.input) {
+            let message = format!(
+                "writing to child stdin failed for {} {}",
+                fs::display(&write.source),
+                write.name
+            );
+            // the binary may simply stop reading before the end of its input
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                log::debug!("{message}\n  {e}");
+            } else {
+                _ = fs::report_custom(message, e);
+            }
+        }
+    })
+}
+
+fn test_binary(context: &TestContext, paths: &CacheEntry, summary: &mut TestSummary) {
+    if !context.options.list {
+        _ = fs::create_dir_all(&context.diff_dir);
+    }
+    if let Some((dir, contents)) = context.dir_samples {
+        test_samples_contents(context, paths, dir, contents, summary);
+        return;
+    }
+    for samples_out in &paths.samples {
+        if interrupt::interrupted() {
+            break;
+        }
+        test_samples_file(context, paths, samples_out, summary);
+    }
+}
+
+/// Collects the samples of --sample-dir, they are tested instead of the samples files
+fn load_dir_samples<'a>(
     args: &Arguments,
-    w_sender: &std::sync::mpsc::Sender<(Box<[u8]>, ChildStdin)>,
-    diff_command: Option<&str>,
+    options: &'a SampleOpts,
+) -> fs::Result<Option<(&'a Path, Vec<u8>)>> {
+    let Some(dir) = &options.sample_dir else {
+        return Ok(None);
+    };
+    let separator = options.separator.as_deref().unwrap_or("---");
+    let contents = samples::samples_from_dir(dir, args.os, separator)?;
+    Ok(Some((dir, contents)))
+}
+
+fn test_samples_file(
+    context: &TestContext,
+    paths: &CacheEntry,
+    samples_out: &Path,
+    summary: &mut TestSummary,
 ) -> Option<()> {
-    let samples_out = paths.samples_out.as_ref()?;
     let contents = fs::read(samples_out).ok()?;
+    let updates = test_samples_contents(context, paths, samples_out, &contents, summary)?;
 
-    let diff_path = out_dir.join("diff");
-    _ = fs::create_dir_all(&diff_path);
-    let file_name = paths.source.file_name().unwrap();
+    if !updates.is_empty() {
+        let mut updated = Vec::with_capacity(contents.len());
+        let mut copied = 0;
+        for (range, actual) in &updates {
+            updated.extend_from_slice(&contents[copied..range.start]);
+            updated.extend_from_slice(actual);
+            copied = range.end;
+        }
+        updated.extend_from_slice(&contents[copied..]);
+
+        log::info!(
+            "updating the expected output of {} tests in `{}`",
+            updates.len(),
+            fs::display(samples_out)
+        );
+        _ = fs::write(samples_out, &updated);
+    }
+
+    Some(())
+}
 
-    let mut sections = samples::SampleIterator::new(&contents)?;
+/// Runs the tests in the contents of a samples file, returns the byte ranges of expected outputs
+/// and their replacements from --update sorted by their position
+fn test_samples_contents(
+    context: &TestContext,
+    paths: &CacheEntry,
+    samples_out: &Path,
+    contents: &[u8],
+    summary: &mut TestSummary,
+) -> Option<Vec<(std::ops::Range<usize>, Vec<u8>)>> {
+    let separator = context.options.sample_options.separator.as_deref();
+    let mut sections = samples::SampleIterator::new(contents, separator)?;
+    // byte ranges of expected outputs in the samples file and their replacements from --update
+    let mut updates = Vec::new();
+    let mut tests = Vec::new();
     while let Some(input) = sections.next() {
-        let input_header = input.header.to_str().ok();
-        let test_name = input_header.and_then(|s| s.strip_suffix(" in"));
-        let output = sections.next();
+        tests.push((input, sections.next()));
+    }
+    if let Some(seed) = context.shuffle_seed {
+        // every samples file gets the same seed so that its order doesn't depend on scheduling
+        tests.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
 
-        if input_header.is_none() {
-            log::error!("input header `{}` isn't UTF8", input.header.to_str_lossy());
-        };
-        if input_header.is_some() && test_name.is_none() {
-            log::error!(
-                "input header `{}` doesn't end with ` in`",
-                input.header.to_str_lossy()
-            );
+    for (input, output) in tests {
+        if interrupt::interrupted() {
+            break;
+        }
+        let Some(test) = samples::validate_test(samples_out, contents, &input, output.as_ref())
+        else {
+            if context.options.list {
+                let header = input.header.to_str_lossy();
+                println!("{} {header} (invalid)", fs::display(&paths.source));
+                continue;
+            }
+            summary.results.push(TestResult {
+                source: paths.source.clone(),
+                name: input.header.to_str_lossy().into_owned(),
+                status: TestStatus::Failed,
+                duration: Duration::ZERO,
+                message: Some("malformed sample".to_owned()),
+            });
+            continue;
         };
-        if output.is_none() {
-            log::error!(
-                "input header `{}` doesn't have an output section",
-                input.header.to_str_lossy()
-            );
+
+        let qualified_name = samples::qualified_name(&paths.source, samples_out, test.name);
+        let test_name = &*qualified_name;
+        let sample_args = &test.args;
+        if let Some(filter) = &context.options.sample_options.filter {
+            if !test_name.contains(filter.as_str()) {
+                log::trace!("skipping `{test_name}`, it doesn't match the filter");
+                summary.filtered += 1;
+                continue;
+            }
         }
-        if input_header.is_none() || test_name.is_none() || output.is_none() {
+        if let Some(failed) = context.failed {
+            if !failed.contains(&(paths.source.clone(), test_name.to_owned())) {
+                log::trace!("skipping `{test_name}`, it passed in the previous run");
+                summary.filtered += 1;
+                continue;
+            }
+        }
+
+        if context.options.list {
+            println!("{} {test_name}", fs::display(&paths.source));
+            continue;
+        }
+        if context.args.dry_run {
+            if let Some(baseline) = &context.options.baseline {
+                fs::print_dry_run(&test_command(&context.run(), baseline, sample_args));
+            }
+            fs::print_dry_run(&test_command(&context.run(), &paths.binary, sample_args));
             continue;
         }
 
-        let mut name = file_name.to_owned();
-        name.push("_");
-        name.push(test_name.unwrap());
-        let test_diff_path = diff_path.join(name);
-        _ = test_samples(
-            test_name.unwrap().as_bytes(),
-            input.body,
-            output.unwrap().body,
+        let mut test_diff_path =
+            diff_file_prefix(&context.diff_dir, &paths.source).into_os_string();
+        test_diff_path.push(test_name);
+        let test_diff_path = PathBuf::from(test_diff_path);
+        let result = test_samples(
+            context,
+            test_name.as_bytes(),
+            &test.input,
+            test.output.body,
             &test_diff_path,
             paths,
-            w_sender,
-            diff_command,
-            args,
+            sample_args,
         );
+        let outcome = result.unwrap_or_else(|_| SampleOutcome {
+            status: TestStatus::Failed,
+            duration: Duration::ZERO,
+            message: Some("failed to run the binary".to_owned()),
+            accepted: None,
+        });
+        if let Some(actual) = outcome.accepted {
+            let expected = test.output.body;
+            if actual.find(sections.separator()).is_some() {
+                log::warn!("not updating `{test_name}`, the output contains the separator");
+            } else {
+                let start = expected.as_ptr() as usize - contents.as_ptr() as usize;
+                updates.push((start..start + expected.len(), actual));
+            }
+        }
+        summary.results.push(TestResult {
+            source: paths.source.clone(),
+            name: test_name.to_owned(),
+            status: outcome.status,
+            duration: outcome.duration,
+            message: outcome.message,
+        });
     }
 
-    Some(())
+    updates.sort_by_key(|(range, _)| range.start);
+    Some(updates)
+}
+
+/// The directory of the files left behind by failed tests
+fn diff_dir(out_dir: &Path, options: &SampleOpts) -> PathBuf {
+    match &options.diff_dir {
+        Some(dir) => dir.clone(),
+        None => out_dir.join("diff"),
+    }
+}
+
+/// The diff files of a test are named `<source path>_<test name>.<extension>` inside the diff
+/// directory, `..` in the source path is replaced so that the files stay inside it
+fn diff_file_prefix(diff_dir: &Path, source: &Path) -> PathBuf {
+    let mut prefix = diff_dir.to_owned();
+    for component in source.components() {
+        match component {
+            Component::Normal(name) => prefix.push(name),
+            Component::ParentDir => prefix.push("_up"),
+            _ => {}
+        }
+    }
+    let mut prefix = prefix.into_os_string();
+    prefix.push("_");
+    PathBuf::from(prefix)
+}
+
+fn test_command(context: &RunContext, binary: &Path, args: &[&str]) -> std::process::Command {
+    let mut command = std::process::Command::new(binary);
+    context.options.env.apply(&mut command);
+    command
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(match context.options.quiet_child {
+            true => std::process::Stdio::null(),
+            false => std::process::Stdio::piped(),
+        });
+
+    #[cfg(unix)]
+    if let Some(megabytes) = context.options.memory_limit {
+        limit_memory(&mut command, megabytes);
+    }
+    command
+}
+
+struct SampleOutcome {
+    status: TestStatus,
+    duration: Duration,
+    message: Option<String>,
+    // the actual output if it was accepted as the new expected output by --update
+    accepted: Option<Vec<u8>>,
+}
+
+/// A single execution of the binary on a sample
+struct SampleRun {
+    status: TestStatus,
+    duration: Duration,
+    exit_status: Option<std::process::ExitStatus>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Whether a repeated run differs from the reference, with a --checker several outputs
+/// can be correct so only the statuses are compared
+fn runs_differ(reference: &SampleRun, run: &SampleRun, options: &SampleOpts) -> bool {
+    run.status != reference.status
+        || (options.checker.is_none()
+            && !compare::outputs_match(&reference.stdout, &run.stdout, options))
 }
 
 fn test_samples(
+    context: &TestContext,
     name: &[u8],
     input: &[u8],
     output: &[u8],
     save_text_path: &Path,
     paths: &CacheEntry,
-    w_sender: &std::sync::mpsc::Sender<(Box<[u8]>, ChildStdin)>,
-    diff_command: Option<&str>,
-    args: &Arguments,
-) -> Result<(), AlreadyReported> {
-    let mut child = std::process::Command::new(&paths.binary)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::inherit())
-        .spawn()
-        .map_err(|e| fs::report_io_error("luanching binary", &paths.binary, e))?;
-
-    let stdin = child.stdin.take().unwrap();
-    let mut stdout = child.stdout.take().unwrap();
-
-    w_sender
-        .send((input.to_owned().into_boxed_slice(), stdin))
-        .expect("Writing thread died!");
+    args: &[&str],
+) -> fs::Result<SampleOutcome> {
+    let baseline_output;
+    let output = match &context.options.baseline {
+        Some(baseline) => {
+            baseline_output = run_baseline(&context.run(), baseline, name, input, paths, args)?;
+            &baseline_output[..]
+        }
+        None => output,
+    };
 
-    let mut child_stdout = Vec::new();
-    if let Err(e) = std::io::Read::read_to_end(&mut stdout, &mut child_stdout) {
-        bail!("Failed to read from child stdout: {e}");
+    let repeat = context.options.repeat.get();
+    let mut runs = Vec::with_capacity(repeat);
+    for _ in 0..repeat {
+        let binary = &paths.binary;
+        runs.push(run_sample(
+            &context.run(),
+            binary,
+            name,
+            input,
+            Some(output),
+            paths,
+            args,
+        )?);
     }
 
-    // we do not care about the exit status
-    // TODO implement a timeout?
-    _ = child.wait();
+    // runs are compared against a passing one if there is any, so that the differing runs
+    // are the ones which failed
+    let reference = runs.iter().position(|r| r.status == TestStatus::Passed);
+    let reference = &runs[reference.unwrap_or(0)];
+    let differs = |run: &SampleRun| runs_differ(reference, run, &context.options.sample_options);
+    let differed = runs.iter().filter(|r| differs(r)).count();
+    let (status, run) = match runs.iter().find(|r| differs(r)) {
+        Some(run) => (TestStatus::Flaky, run),
+        None => (reference.status, reference),
+    };
+    let duration = runs.iter().map(|r| r.duration).max().unwrap();
 
-    let source = paths.source.display();
+    let source = fs::display(&paths.source);
     let display = name.to_str_lossy();
+    let (label, color) = status.label();
+    let label = style(context.args, color).paint(label);
 
-    // janky configurable color
-    let (red, green) = match args.color == ColorChoice::Never {
-        true => (Color::Default, Color::Default),
-        false => (Color::LightRed, Color::LightGreen),
-    };
-    let err = red.paint("Err");
-    let ok = green.paint("Ok");
+    let seconds = format!("{:.2}s", duration.as_secs_f64());
+
+    if status == TestStatus::Passed {
+        let level = passing_level(context.options);
+        log::log!(level, "{source} {display} {label} ({seconds})");
+        return Ok(SampleOutcome {
+            status,
+            duration,
+            message: None,
+            accepted: None,
+        });
+    }
+
+    let mut notes = vec![seconds.clone()];
+    if status == TestStatus::Flaky {
+        notes.insert(0, format!("{differed}/{repeat} runs differed"));
+    }
+    if !run.stderr.is_empty() {
+        notes.push("wrote to stderr".to_owned());
+    }
+    let mut exit_note = None;
+    if let Some(exit) = run.exit_status.filter(|s| !s.success()) {
+        use std::os::unix::process::ExitStatusExt;
+        exit_note = match (exit.code(), exit.signal()) {
+            (Some(code), _) => Some(format!("exit code {code}")),
+            (_, Some(signal)) => Some(format!("signal {signal}")),
+            _ => None,
+        };
+        notes.extend(exit_note.clone());
+        if status == TestStatus::Crashed
+            && context.options.sample_options.memory_limit.is_some()
+            && may_be_out_of_memory(exit)
+        {
+            notes.push("possibly over the memory limit".to_owned());
+        }
+    }
+    // the diff and the prompt can't be drawn over by the progress bar
+    let outcome = progress::suspend(|| {
+        let _console = context.console.lock().unwrap();
+        log::info!("{source} {display} {label} ({})", notes.join(", "));
+
+        let message = match status {
+            TestStatus::Timeout => format!("timed out after {seconds}"),
+            TestStatus::MemoryLimit => "exceeded the memory limit".to_owned(),
+            TestStatus::OutputLimit => format!(
+                "output too large, printed more than {} bytes",
+                context.options.sample_options.max_output_bytes
+            ),
+            TestStatus::Crashed => format!("crashed with {}", exit_note.unwrap_or_default()),
+            TestStatus::Flaky => format!("{differed}/{repeat} runs differed"),
+            _ if context.options.sample_options.checker.is_some() => {
+                "rejected by the checker".to_owned()
+            }
+            _ => compare::first_difference(
+                &compare::select(output, &context.options.sample_options),
+                &compare::select(&run.stdout, &context.options.sample_options),
+            ),
+        };
+        // a pointer into the outputs without opening the diff
+        if status == TestStatus::Failed && context.options.sample_options.checker.is_none() {
+            log::info!("  {message}");
+        }
 
-    if child_stdout != output {
-        log::info!("{source} {display} {err}");
         _ = diff_failed(
             save_text_path,
             input,
             output,
-            &child_stdout,
-            args,
-            diff_command,
+            &run.stdout,
+            &run.stderr,
+            context.args,
+            context.options,
         );
-    } else {
-        log::info!("{source} {display} {ok}");
+        if context.options.diff_batch {
+            context
+                .batch_diffs
+                .lock()
+                .unwrap()
+                .push(save_text_path.to_owned());
+        }
+
+        let accepted = context.options.update
+            && status == TestStatus::Failed
+            && confirm(context.args, "Update the expected output?", false);
+        SampleOutcome {
+            status,
+            duration,
+            message: Some(message),
+            accepted: accepted.then(|| run.stdout.clone()),
+        }
+    });
+    Ok(outcome)
+}
+
+/// Runs the --baseline binary on the input, returns its output which the target has to match
+fn run_baseline(
+    context: &RunContext,
+    baseline: &Path,
+    name: &[u8],
+    input: &[u8],
+    paths: &CacheEntry,
+    args: &[&str],
+) -> fs::Result<Vec<u8>> {
+    // the baseline provides the expected output, there is nothing to compare it with
+    let run = run_sample(context, baseline, name, input, None, paths, args)?;
+    match run.status {
+        TestStatus::Passed => Ok(run.stdout),
+        status => bail!(
+            "the baseline `{}` {} on `{}`",
+            fs::display(baseline),
+            status.summary(),
+            name.to_str_lossy()
+        ),
     }
-    Ok(())
 }
 
-fn diff_failed(
-    path: &Path,
+/// Runs the binary once and checks its output against the expected one, if there is one
+fn run_sample(
+    context: &RunContext,
+    binary: &Path,
+    name: &[u8],
+    input: &[u8],
+    output: Option<&[u8]>,
+    paths: &CacheEntry,
+    args: &[&str],
+) -> fs::Result<SampleRun> {
+    let mut command = test_command(context, binary, args);
+    let start = Instant::now();
+    let mut child = command
+        .spawn()
+        .map_err(|e| fs::report_io_error("luanching binary", binary, e))?;
+
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    // missing with --quiet-child
+    let stderr = child.stderr.take();
+
+    let writer = spawn_writer(StdinWrite {
+        input: input.to_owned().into_boxed_slice(),
+        stdin,
+        source: paths.source.clone(),
+        name: name.to_str_lossy().into_owned(),
+    });
+
+    // the pipes are read on separate threads so that we can kill the child if it times out
+    // or prints too much
+    let limit = context.options.max_output_bytes;
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let stdout_reader = spawn_reader(stdout, limit, overflowed.clone());
+    let stderr_reader = stderr.map(|stderr| spawn_reader(stderr, limit, overflowed.clone()));
+
+    let timeout = context.options.timeout;
+    let (timed_out, exit_status) = match fs::wait_timeout(&mut child, timeout, &overflowed) {
+        Ok(Some(status)) => (false, Some(status)),
+        Ok(None) => {
+            _ = child.kill();
+            _ = child.wait();
+            (true, None)
+        }
+        Err(e) => {
+            _ = child.kill();
+            bail!("Failed to wait for child: {e}");
+        }
+    };
+    let duration = start.elapsed();
+
+    // the pipe is broken once the child exits, so the writer can't be stuck at this point
+    writer.join().expect("Writing thread panicked!");
+    let child_stdout = match stdout_reader.join().expect("Reading thread panicked!") {
+        Ok(ok) => ok,
+        Err(e) => bail!("Failed to read from child stdout: {e}"),
+    };
+    let child_stderr = match stderr_reader.map(|r| r.join().expect("Reading thread panicked!")) {
+        Some(Ok(ok)) => ok,
+        Some(Err(e)) => bail!("Failed to read from child stderr: {e}"),
+        None => Vec::new(),
+    };
+
+    let crashed = exit_status.is_some_and(|s| !s.success());
+    let status = if overflowed.load(Ordering::Relaxed) {
+        TestStatus::OutputLimit
+    } else if timed_out {
+        TestStatus::Timeout
+    } else if context.options.memory_limit.is_some()
+        && exit_status.is_some_and(|s| allocation_failed(s, &child_stderr))
+    {
+        TestStatus::MemoryLimit
+    } else if crashed && !context.options.ignore_exit_status {
+        TestStatus::Crashed
+    } else if output
+        .map(|output| output_accepted(context, name, input, output, &child_stdout, paths))
+        .transpose()?
+        == Some(false)
+    {
+        TestStatus::Failed
+    } else {
+        TestStatus::Passed
+    };
+
+    Ok(SampleRun {
+        status,
+        duration,
+        exit_status,
+        stdout: child_stdout,
+        stderr: child_stderr,
+    })
+}
+
+/// Compares the outputs, or runs the --checker on them
+fn output_accepted(
+    context: &RunContext,
+    name: &[u8],
     input: &[u8],
     expected: &[u8],
     actual: &[u8],
-    args: &Arguments,
-    diff_command: Option<&str>,
-) -> fs::Result<()> {
-    let input_path = path.with_extension("in");
-    let output_path = path.with_extension("out");
-    let actual_path = path.with_extension("out.actual");
+    paths: &CacheEntry,
+) -> fs::Result<bool> {
+    let Some(checker) = &context.options.checker else {
+        return Ok(compare::outputs_match(expected, actual, context.options));
+    };
+
+    // the files are where a failed test leaves them, they are removed if the output is accepted
+    let mut path = diff_file_prefix(context.diff_dir, &paths.source).into_os_string();
+    path.push(name.to_os_str_lossy());
+    let path = PathBuf::from(path);
+    let input_path = with_extension(&path, "in");
+    let output_path = with_extension(&path, "out");
+    let actual_path = with_extension(&path, "out.actual");
 
+    _ = fs::create_dir_all(path.parent().unwrap());
     fs::write(&input_path, input)?;
     fs::write(&output_path, expected)?;
     fs::write(&actual_path, actual)?;
 
-    let Some(diff) = diff_command else {
-        return Ok(());
-    };
+    let mut builder = std::process::Command::new("sh");
+    builder
+        .arg("-c")
+        .arg(checker)
+        .env("INPUT", &input_path)
+        .env("EXPECTED", &output_path)
+        .env("ACTUAL", &actual_path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null());
+    context.args.apply_child_color(&mut builder);
 
-    let should_diff = match args.ask {
+    let accepted = check_status("Checker", builder.status()).is_ok();
+    if accepted {
+        for path in [input_path, output_path, actual_path] {
+            _ = std::fs::remove_file(path);
+        }
+    }
+    Ok(accepted)
+}
+
+// the test name may contain dots, so the extension is appended rather than replaced
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Limits the address space of the child process
+#[cfg(unix)]
+fn limit_memory(command: &mut std::process::Command, megabytes: u64) {
+    use std::os::unix::process::CommandExt;
+
+    let bytes = megabytes.saturating_mul(1024 * 1024) as libc::rlim_t;
+    // SAFETY: setrlimit is async-signal-safe and doesn't allocate
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit {
+                rlim_cur: bytes,
+                rlim_max: bytes,
+            };
+            match libc::setrlimit(libc::RLIMIT_AS, &limit) {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        });
+    }
+}
+
+/// Whether the process aborted after reporting a failed allocation, only C++ with an uncaught
+/// `std::bad_alloc` and Rust say so, a null pointer from malloc in C just crashes
+fn allocation_failed(status: std::process::ExitStatus, stderr: &[u8]) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    const MESSAGES: [&[u8]; 2] = [b"std::bad_alloc", b"memory allocation of"];
+    status.signal() == Some(libc::SIGABRT)
+        && MESSAGES
+            .iter()
+            .any(|message| stderr.windows(message.len()).any(|w| w == *message))
+}
+
+/// Whether the process was terminated by a signal which a failed allocation can cause
+fn may_be_out_of_memory(status: std::process::ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    matches!(
+        status.signal(),
+        Some(libc::SIGABRT | libc::SIGSEGV | libc::SIGBUS | libc::SIGKILL)
+    )
+}
+
+/// Reads the pipe until it is closed or `limit` bytes are exceeded, in which case `overflowed`
+/// is set and the output is cut off at the limit
+fn spawn_reader(
+    pipe: impl std::io::Read + Send + 'static,
+    limit: u64,
+    overflowed: Arc<AtomicBool>,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    use std::io::Read;
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.take(limit.saturating_add(1)).read_to_end(&mut buf)?;
+        if buf.len() as u64 > limit {
+            buf.truncate(limit as usize);
+            overflowed.store(true, Ordering::Relaxed);
+        }
+        Ok(buf)
+    })
+}
+
+/// Asks the user a yes or no question according to --ask, an empty answer picks the default
+fn confirm(args: &Arguments, question: &str, default: bool) -> bool {
+    match args.ask {
         cli::Interactivity::Skip => false,
         cli::Interactivity::No => true,
         cli::Interactivity::Yes => {
+            let choices = match default {
+                true => "[Y/n]",
+                false => "[y/N]",
+            };
             let mut line = String::new();
-            let should_diff = loop {
-                eprint!("View diff? [Y/n] ");
+            loop {
+                eprint!("{question} {choices} ");
                 line.clear();
                 if std::io::stdin().read_line(&mut line).is_err() {
                     break false;
                 }
                 match line.trim_start().chars().next() {
-                    Some('Y' | 'y') | None => break true,
+                    Some('Y' | 'y') => break true,
                     Some('N' | 'n') => break false,
+                    None => break default,
                     _ => {}
                 }
-            };
-            should_diff
+            }
+        }
+    }
+}
+
+/// Runs the --diff command once with the files of all failed tests
+fn diff_batch(diff: &str, paths: &[PathBuf], args: &Arguments) {
+    let list = |extension: &str| {
+        let files = paths.iter().map(|path| with_extension(path, extension));
+        let mut list = OsString::new();
+        for (i, file) in files.enumerate() {
+            if i > 0 {
+                list.push("\n");
+            }
+            list.push(file);
         }
+        list
     };
 
-    if should_diff {
-        let mut builder = std::process::Command::new("sh");
-        builder
-            .arg("-c")
-            .arg(diff)
-            .env("INPUT", input_path)
-            .env("EXPECTED", output_path)
-            .env("ACTUAL", actual_path);
+    let mut builder = std::process::Command::new("sh");
+    builder
+        .arg("-c")
+        .arg(diff)
+        .env("INPUT", list("in"))
+        .env("EXPECTED", list("out"))
+        .env("ACTUAL", list("out.actual"));
+    args.apply_child_color(&mut builder);
+
+    print_args(&builder);
+    _ = check_status("Diff command", builder.status());
+}
+
+fn diff_failed(
+    path: &Path,
+    input: &[u8],
+    expected: &[u8],
+    actual: &[u8],
+    stderr: &[u8],
+    args: &Arguments,
+    options: &TestOpts,
+) -> fs::Result<()> {
+    let input_path = with_extension(path, "in");
+    let output_path = with_extension(path, "out");
+    let actual_path = with_extension(path, "out.actual");
+    let stderr_path = with_extension(path, "err");
+
+    _ = fs::create_dir_all(path.parent().unwrap());
+    fs::write(&input_path, input)?;
+    fs::write(&output_path, expected)?;
+    fs::write(&actual_path, actual)?;
+    fs::write(&stderr_path, stderr)?;
+
+    if options.diff_batch || !confirm(args, "View diff?", true) {
+        return Ok(());
+    }
+
+    let color = args.color != ColorChoice::Never;
+    let lines = expected.lines().count().max(actual.lines().count());
+    match &options.diff {
+        Some(diff) => {
+            let mut builder = std::process::Command::new("sh");
+            builder
+                .arg("-c")
+                .arg(diff)
+                .env("INPUT", input_path)
+                .env("EXPECTED", output_path)
+                .env("ACTUAL", actual_path);
+            args.apply_child_color(&mut builder);
 
-        print_args(&builder);
-        _ = check_status("Diff command", builder.status());
+            print_args(&builder);
+            _ = check_status("Diff command", builder.status());
+        }
+        None if options.verbose_diff.is_some_and(|max| lines <= max) => {
+            diff::print_side_by_side(expected, actual, color)
+        }
+        None => diff::print_unified_diff(expected, actual, color),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_reported_allocation_failures_exceed_the_memory_limit() {
+        use std::os::unix::process::ExitStatusExt;
+        let signaled = |signal| std::process::ExitStatus::from_raw(signal);
+        let bad_alloc = b"terminate called after throwing an instance of 'std::bad_alloc'\n";
+
+        assert!(allocation_failed(signaled(libc::SIGABRT), bad_alloc));
+        assert!(allocation_failed(
+            signaled(libc::SIGABRT),
+            b"memory allocation of 8 bytes failed"
+        ));
+        assert!(!allocation_failed(
+            signaled(libc::SIGABRT),
+            b"assertion failed"
+        ));
+        assert!(!allocation_failed(signaled(libc::SIGSEGV), bad_alloc));
+        assert!(may_be_out_of_memory(signaled(libc::SIGSEGV)));
+        assert!(!may_be_out_of_memory(std::process::ExitStatus::from_raw(
+            1 << 8
+        )));
+    }
+
+    #[test]
+    fn diff_files_of_same_named_sources_differ() {
+        let diff_dir = Path::new("out/diff");
+        let first = diff_file_prefix(diff_dir, Path::new("a/foo.c"));
+        let second = diff_file_prefix(diff_dir, Path::new("b/foo.c"));
+        assert_ne!(first, second);
+        assert_eq!(first, Path::new("out/diff/a/foo.c_"));
+
+        // the files stay inside the diff directory
+        let outside = diff_file_prefix(diff_dir, Path::new("../foo.c"));
+        assert_eq!(outside, Path::new("out/diff/_up/foo.c_"));
+    }
+
+    #[test]
+    fn prompting_is_disabled_without_terminal() {
+        use cli::Interactivity::*;
+        assert_eq!(interactivity(Yes, false, false), Skip);
+        assert_eq!(interactivity(Yes, false, true), Yes);
+        // explicit choices which don't prompt are kept
+        assert_eq!(interactivity(No, false, false), No);
+        assert_eq!(interactivity(Skip, false, true), Skip);
+        assert_eq!(interactivity(Yes, true, false), No);
+    }
+
+    #[test]
+    fn accepted_outputs_differ_only_without_checker() {
+        let options = |args: &[&str]| {
+            let args = ["testprog", "test"].iter().chain(args);
+            match Arguments::parse_from(args).command {
+                Command::Test { test_options, .. } => test_options.sample_options,
+                _ => unreachable!(),
+            }
+        };
+        let run = |status, stdout: &[u8]| SampleRun {
+            status,
+            duration: Duration::ZERO,
+            exit_status: None,
+            stdout: stdout.to_vec(),
+            stderr: Vec::new(),
+        };
+        let reference = run(TestStatus::Passed, b"1 2\n");
+        let other_answer = run(TestStatus::Passed, b"2 1\n");
+        let failed = run(TestStatus::Failed, b"1 1\n");
+
+        let plain = options(&["--repeat", "3"]);
+        assert!(runs_differ(&reference, &other_answer, &plain));
+        assert!(!runs_differ(&reference, &reference, &plain));
+        let checked = options(&["--repeat", "3", "--checker", "./check"]);
+        assert!(!runs_differ(&reference, &other_answer, &checked));
+        assert!(runs_differ(&reference, &failed, &checked));
+    }
+
+    #[test]
+    fn large_input_is_echoed() {
+        let args = Arguments::parse_from(["testprog", "test", "--timeout", "20"]);
+        let Command::Test { test_options, .. } = &args.command else {
+            unreachable!()
+        };
+        let context = RunContext {
+            args: &args,
+            options: &test_options.sample_options,
+            diff_dir: Path::new("target/test-tmp/echo"),
+        };
+        let paths =
+            CacheEntry::from_serialized(Path::new("cat.c"), 0, Vec::new(), None, Path::new("out"));
+        // many times the size of a pipe buffer, `cat` writes the output before reading all of the input
+        let input = (0..1_000_000).map(|i| format!("{i}\n")).collect::<String>();
+        let input = input.as_bytes();
+
+        let run = run_sample(
+            &context,
+            Path::new("cat"),
+            b"echo",
+            input,
+            Some(input),
+            &paths,
+            &[],
+        );
+        let run = run.unwrap();
+        assert_eq!(run.status, TestStatus::Passed);
+        assert_eq!(run.stdout.len(), input.len());
+    }
+}
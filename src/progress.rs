@@ -0,0 +1,81 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// the bar which is currently drawn, the logger needs it to hide the bar while writing
+static CURRENT: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+thread_local! {
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Allows showing progress bars, without it they are never drawn
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether progress bars are shown
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs `fun` with the progress bar hidden so that it can write to the terminal
+pub fn suspend<R>(fun: impl FnOnce() -> R) -> R {
+    let bar = CURRENT.lock().unwrap().clone();
+    match bar {
+        // the bar is locked while suspended, a nested call would deadlock
+        Some(bar) if !SUSPENDED.get() => {
+            SUSPENDED.set(true);
+            let result = bar.suspend(fun);
+            SUSPENDED.set(false);
+            result
+        }
+        _ => fun(),
+    }
+}
+
+/// A progress bar which is cleared once dropped
+pub struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    /// Shows a bar like `built 37/100`, it stays hidden unless enabled
+    pub fn start(len: usize, verb: &str) -> Progress {
+        if !enabled() || len == 0 {
+            return Progress { bar: None };
+        }
+
+        let template = format!("{verb} {{pos}}/{{len}} {{bar:40}} {{wide_msg}}");
+        let bar = ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stderr())
+            .with_style(ProgressStyle::with_template(&template).unwrap());
+        *CURRENT.lock().unwrap() = Some(bar.clone());
+        Progress { bar: Some(bar) }
+    }
+    pub fn set_message(&self, message: String) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(message);
+        }
+    }
+    pub fn inc(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            CURRENT.lock().unwrap().take();
+            bar.finish_and_clear();
+        }
+    }
+}
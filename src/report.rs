@@ -0,0 +1,230 @@
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fs, TestResult, TestStatus};
+
+/// The results of the previous run in the output directory, used by `test --failed`
+pub const LAST_RESULTS_FILE: &str = "last_results.json";
+
+#[derive(Serialize)]
+struct JsonResult<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    source: &'a Path,
+    name: &'a str,
+    status: &'static str,
+    duration_ms: u128,
+}
+
+/// Writes the results as a JSON array of objects, each labeled with the tag if there is one
+pub fn write_json(path: &Path, results: &[TestResult], tag: Option<&str>) -> fs::Result<()> {
+    let results = results
+        .iter()
+        .map(|r| JsonResult {
+            tag,
+            source: &r.source,
+            name: &r.name,
+            status: r.status.id(),
+            duration_ms: r.duration.as_millis(),
+        })
+        .collect::<Vec<_>>();
+    let serialized = serde_json::to_string_pretty(&results).unwrap();
+
+    log::debug!("writing report to `{}`", fs::display(path));
+    fs::write(path, serialized.as_bytes())
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    source: PathBuf,
+    name: String,
+    status: String,
+    #[serde(default)]
+    duration_ms: u128,
+}
+
+fn read_saved(path: &Path) -> fs::Result<Vec<SavedResult>> {
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str::<Vec<SavedResult>>(&contents).map_err(|e| {
+        let message = format!("failed to parse the results `{}`", fs::display(path));
+        fs::report_custom(message, e)
+    })
+}
+
+/// Adds the results to the ones saved by previous runs, replacing those of the same tests,
+/// so that `test --failed` still knows about the failures of targets which weren't tested since
+pub fn update_last_results(
+    path: &Path,
+    results: &[TestResult],
+    tag: Option<&str>,
+) -> fs::Result<()> {
+    // unreadable results are replaced, they would fail every later run otherwise
+    let mut saved = match path.is_file() {
+        true => read_saved(path).unwrap_or_default(),
+        false => Vec::new(),
+    };
+    let tested = results
+        .iter()
+        .map(|r| (r.source.as_path(), r.name.as_str()))
+        .collect::<HashSet<_>>();
+    saved.retain(|r| !tested.contains(&(r.source.as_path(), r.name.as_str())));
+    saved.extend(results.iter().map(|r| SavedResult {
+        tag: tag.map(str::to_owned),
+        source: r.source.clone(),
+        name: r.name.clone(),
+        status: r.status.id().to_owned(),
+        duration_ms: r.duration.as_millis(),
+    }));
+    let serialized = serde_json::to_string_pretty(&saved).unwrap();
+
+    log::debug!("updating the results in `{}`", fs::display(path));
+    fs::write(path, serialized.as_bytes())
+}
+
+/// Reads results written by `write_json`, returns the source and name of the tests which didn't pass
+pub fn read_failed(path: &Path) -> fs::Result<HashSet<(PathBuf, String)>> {
+    let failed = read_saved(path)?
+        .into_iter()
+        .filter(|r| r.status != TestStatus::Passed.id())
+        .map(|r| (r.source, r.name))
+        .collect();
+    Ok(failed)
+}
+
+/// Writes the results as a JUnit XML document, mismatched outputs are reported as failures
+/// and tests which didn't finish normally as errors, the tag is used as the name of the testsuites
+pub fn write_junit(path: &Path, results: &[TestResult], tag: Option<&str>) -> fs::Result<()> {
+    let mut suites: Vec<(&Path, Vec<&TestResult>)> = Vec::new();
+    for result in results {
+        match suites
+            .iter_mut()
+            .find(|(source, _)| *source == result.source)
+        {
+            Some((_, suite)) => suite.push(result),
+            None => suites.push((&result.source, vec![result])),
+        }
+    }
+
+    let mut buf = String::new();
+    _ = writeln!(buf, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    match tag {
+        Some(tag) => _ = writeln!(buf, r#"<testsuites name="{}">"#, escape(tag)),
+        None => _ = writeln!(buf, "<testsuites>"),
+    }
+    for (source, suite) in suites {
+        let count = |f: fn(TestStatus) -> bool| suite.iter().filter(|r| f(r.status)).count();
+        let failures = count(|s| matches!(s, TestStatus::Failed | TestStatus::Flaky));
+        let errors = count(|s| {
+            !matches!(
+                s,
+                TestStatus::Passed | TestStatus::Failed | TestStatus::Flaky
+            )
+        });
+        let seconds = suite.iter().map(|r| r.duration.as_secs_f64()).sum::<f64>();
+        _ = writeln!(
+            buf,
+            r#"  <testsuite name="{}" tests="{}" failures="{failures}" errors="{errors}" time="{seconds:.3}">"#,
+            escape(&source.to_string_lossy()),
+            suite.len(),
+        );
+        for result in suite {
+            _ = write!(
+                buf,
+                r#"    <testcase classname="{}" name="{}" time="{:.3}""#,
+                escape(&source.to_string_lossy()),
+                escape(&result.name),
+                result.duration.as_secs_f64()
+            );
+            let element = match result.status {
+                TestStatus::Passed => {
+                    _ = writeln!(buf, "/>");
+                    continue;
+                }
+                TestStatus::Failed | TestStatus::Flaky => "failure",
+                _ => "error",
+            };
+            let message = result.message.as_deref().unwrap_or("");
+            _ = writeln!(buf, ">");
+            _ = writeln!(
+                buf,
+                r#"      <{element} type="{}" message="{}"/>"#,
+                result.status.id(),
+                escape(message)
+            );
+            _ = writeln!(buf, "    </testcase>");
+        }
+        _ = writeln!(buf, "  </testsuite>");
+    }
+    _ = writeln!(buf, "</testsuites>");
+
+    log::debug!("writing JUnit report to `{}`", fs::display(path));
+    fs::write(path, buf.as_bytes())
+}
+
+fn escape(str: &str) -> String {
+    let mut escaped = String::with_capacity(str.len());
+    for c in str.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            // other control characters are not allowed in XML 1.0
+            c if c.is_control() && c != '\t' => escaped.push('\u{FFFD}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn result(source: &str, name: &str, status: TestStatus) -> TestResult {
+        TestResult {
+            source: PathBuf::from(source),
+            name: name.to_owned(),
+            status,
+            duration: Duration::ZERO,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn failures_of_untested_targets_are_kept() {
+        let dir = Path::new("target/test-tmp/last-results");
+        _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join(LAST_RESULTS_FILE);
+        let failed = |source: &str, name: &str| (PathBuf::from(source), name.to_owned());
+
+        let first = [
+            result("a.cpp", "one", TestStatus::Failed),
+            result("a.cpp", "two", TestStatus::Crashed),
+        ];
+        update_last_results(&path, &first, None).unwrap();
+        update_last_results(&path, &[result("b.cpp", "one", TestStatus::Passed)], None).unwrap();
+        let expected = HashSet::from([failed("a.cpp", "one"), failed("a.cpp", "two")]);
+        assert_eq!(read_failed(&path).unwrap(), expected);
+
+        // a rerun replaces only the results of the tests which ran
+        update_last_results(&path, &[result("a.cpp", "one", TestStatus::Passed)], None).unwrap();
+        assert_eq!(
+            read_failed(&path).unwrap(),
+            HashSet::from([failed("a.cpp", "two")])
+        );
+
+        _ = std::fs::remove_dir_all(dir);
+    }
+}
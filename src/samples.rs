@@ -1,36 +1,61 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     ffi::OsString,
     io::Write,
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
 };
 
 use bstr::ByteSlice;
+use serde::Serialize;
 
 use crate::{
-    cli::{Arguments, Os},
-    fs::{self, check_status, print_args, visit_files, TraversalEvent, TraversalResponse},
+    bail,
+    cli::{Arguments, ConvertOpts, Os},
+    fs::{
+        self, check_status, print_args, visit_files, AlreadyReported, TraversalEvent,
+        TraversalResponse,
+    },
 };
 
+#[derive(Clone, Copy)]
 pub struct Sample<'a> {
     pub header: &'a [u8],
     pub body: &'a [u8],
 }
 
 pub struct SampleIterator<'a> {
+    separator: &'a [u8],
     inner: bstr::Split<'a, 'a>,
 }
 
 impl<'a> SampleIterator<'a> {
-    pub fn new(str: &'a [u8]) -> Option<SampleIterator<'a>> {
+    /// Splits the samples by the separator on the first line, if `expected` is provided
+    /// that line must be equal to it
+    pub fn new(str: &'a [u8], expected: Option<&str>) -> Option<SampleIterator<'a>> {
         // can't use bstr::split because we need the splitting slice to include the newline
         let newline_index = str.bytes().position(|c| c == b'\n')?;
         let (separator, remaining) = str.split_at(newline_index + 1);
+        if let Some(expected) = expected {
+            if separator.trim_end() != expected.as_bytes() {
+                log::error!(
+                    "samples file starts with `{}` instead of the separator `{expected}`",
+                    separator.trim_end().to_str_lossy()
+                );
+                return None;
+            }
+        }
         let iterator = SampleIterator {
+            separator,
             inner: remaining.split_str(separator),
         };
         Some(iterator)
     }
+    /// The separator line including its line ending
+    pub fn separator(&self) -> &'a [u8] {
+        self.separator
+    }
 }
 
 impl<'a> Iterator for SampleIterator<'a> {
@@ -42,140 +67,595 @@ impl<'a> Iterator for SampleIterator<'a> {
     }
 }
 
+/// An input header `<name> in` or `<name> in gz` for a compressed input, either can be
+/// followed by ` [<args>]` with the whitespace separated command-line arguments of the sample
+pub struct InputHeader<'a> {
+    pub name: &'a str,
+    pub args: Vec<&'a str>,
+    pub compressed: bool,
+}
+
+pub fn parse_input_header(header: &str) -> Option<InputHeader<'_>> {
+    let (header, args) = match header.strip_suffix(']').and_then(|h| h.rsplit_once(" [")) {
+        Some((header, args)) => (header, args.split_ascii_whitespace().collect()),
+        None => (header, Vec::new()),
+    };
+    let (name, compressed) = match header.strip_suffix(" in") {
+        Some(name) => (name, false),
+        None => (header.strip_suffix(" in gz")?, true),
+    };
+    Some(InputHeader {
+        name,
+        args,
+        compressed,
+    })
+}
+
+/// Decodes the body of an input marked with `gz`, it is gzip compressed and base64 encoded
+pub fn decompress_input(body: &[u8]) -> Result<Vec<u8>, String> {
+    use base64::Engine;
+    let encoded = body
+        .iter()
+        .copied()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    let compressed = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| e.to_string())?;
+    let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+    let mut decompressed = Vec::new();
+    std::io::Read::read_to_end(&mut decoder, &mut decompressed).map_err(|e| e.to_string())?;
+    Ok(decompressed)
+}
+
+/// Encodes an input for a section marked with `gz`, the base64 is wrapped into lines
+fn compress_input(input: &[u8]) -> Vec<u8> {
+    use base64::Engine;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    _ = encoder.write_all(input);
+    let compressed = encoder.finish().unwrap();
+    let encoded = base64::engine::general_purpose::STANDARD.encode(compressed);
+
+    let mut body = Vec::with_capacity(encoded.len() + encoded.len() / 76 + 1);
+    for line in encoded.as_bytes().chunks(76) {
+        body.extend_from_slice(line);
+        body.push(b'\n');
+    }
+    body
+}
+
+/// Returns the samples files of a source `foo.c`, `foo.samples` followed by any
+/// `foo.<group>.samples` next to it
+pub fn samples_files(source: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let default = source.with_extension("samples");
+    if default.is_file() {
+        files.push(default);
+    }
+
+    let Some(stem) = source.file_stem() else {
+        return files;
+    };
+    let dir = match source.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    let mut prefix = stem.to_owned();
+    prefix.push(".");
+    let mut groups = entries
+        .flatten()
+        .map(|entry| entry.file_name())
+        .filter(|name| {
+            let name = name.as_bytes();
+            name.len() > prefix.len() + ".samples".len()
+                && name.starts_with(prefix.as_bytes())
+                && name.ends_with(b".samples")
+        })
+        .map(|name| source.with_file_name(name))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    groups.sort();
+    files.extend(groups);
+    files
+}
+
+/// Prefixes the name of a test from `foo.<group>.samples` with `<group>/` so that tests
+/// from different files of the same source don't collide
+pub fn qualified_name<'a>(source: &Path, samples_file: &Path, name: &'a str) -> Cow<'a, str> {
+    let group = (|| {
+        let stem = source.file_stem()?.to_str()?;
+        let file_name = samples_file.file_name()?.to_str()?;
+        file_name
+            .strip_prefix(stem)?
+            .strip_prefix('.')?
+            .strip_suffix(".samples")
+    })();
+    match group {
+        Some(group) => Cow::Owned(format!("{group}/{name}")),
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// A well-formed test of a samples file
+pub struct TestCase<'a> {
+    pub name: &'a str,
+    pub args: Vec<&'a str>,
+    // decompressed if the input is marked with `gz`
+    pub input: Cow<'a, [u8]>,
+    pub output: Sample<'a>,
+}
+
+/// Validates a test made of an input and an output section and logs every problem with the
+/// line of its input header, returns the test if it is well-formed
+pub fn validate_test<'a>(
+    file: &Path,
+    contents: &[u8],
+    input: &Sample<'a>,
+    output: Option<&Sample<'a>>,
+) -> Option<TestCase<'a>> {
+    let offset = input.header.as_ptr() as usize - contents.as_ptr() as usize;
+    let line = contents[..offset].iter().filter(|&&c| c == b'\n').count() + 1;
+    let location = format!("{}:{line}", fs::display(file));
+
+    let input_header = input.header.to_str().ok();
+    let parsed_header = input_header.and_then(parse_input_header);
+
+    if input_header.is_none() {
+        log::error!(
+            "{location}: input header `{}` isn't UTF8",
+            input.header.to_str_lossy()
+        );
+    };
+    if input_header.is_some() && parsed_header.is_none() {
+        log::error!(
+            "{location}: input header `{}` doesn't end with ` in`, ` in gz` or either followed by ` [<args>]`",
+            input.header.to_str_lossy()
+        );
+    };
+    if output.is_none() {
+        log::error!(
+            "{location}: input header `{}` doesn't have an output section",
+            input.header.to_str_lossy()
+        );
+    }
+    let header = parsed_header?;
+    let output = output?;
+
+    let input = match header.compressed {
+        true => match decompress_input(input.body) {
+            Ok(decompressed) => Cow::Owned(decompressed),
+            Err(e) => {
+                log::error!(
+                    "{location}: the compressed input of `{}` is invalid: {e}",
+                    header.name
+                );
+                return None;
+            }
+        },
+        false => Cow::Borrowed(input.body),
+    };
+    Some(TestCase {
+        name: header.name,
+        args: header.args,
+        input,
+        output: *output,
+    })
+}
+
+/// Checks the structure of a samples file, fails if any of its tests is malformed
+pub fn subcommand_check(file: &Path, separator: Option<&str>) -> fs::Result<()> {
+    let contents = fs::read(file)?;
+    if !contents.contains(&b'\n') {
+        bail!(
+            "`{}` doesn't start with a separator line",
+            fs::display(file)
+        );
+    }
+    let mut sections = SampleIterator::new(&contents, separator).ok_or(AlreadyReported)?;
+
+    let mut valid = 0;
+    let mut malformed = 0;
+    while let Some(input) = sections.next() {
+        let output = sections.next();
+        match validate_test(file, &contents, &input, output.as_ref()) {
+            Some(_) => valid += 1,
+            None => malformed += 1,
+        }
+    }
+
+    log::info!("{valid} valid tests in `{}`", fs::display(file));
+    if malformed > 0 {
+        bail!("{malformed} malformed tests in `{}`", fs::display(file));
+    }
+    Ok(())
+}
+
+// sample archives are shallow, anything deeper is junk
+const MAX_SAMPLE_DEPTH: usize = 8;
+
 #[derive(Default)]
 struct SampleFiles {
     input: Option<PathBuf>,
     output: Option<PathBuf>,
 }
 
+/// Which sample files of a directory are collected, see the options of `convert`
+#[derive(Default)]
+struct SampleSelection<'a> {
+    sample_subdirs: &'a [OsString],
+    exclude: &'a [OsString],
+    follow_symlinks: bool,
+    strict: bool,
+}
+
+impl<'a> SampleSelection<'a> {
+    fn new(options: &'a ConvertOpts) -> Self {
+        Self {
+            sample_subdirs: &options.sample_subdirs,
+            exclude: &options.exclude,
+            follow_symlinks: options.follow_symlinks,
+            strict: options.strict,
+        }
+    }
+}
+
 fn collect_sample_files(
     dir: &Path,
     target_os: Os,
-    sample_subdirs: &[OsString],
-) -> Vec<(String, SampleFiles)> {
+    options: &SampleSelection,
+) -> fs::Result<Vec<(String, PathBuf, PathBuf)>> {
+    // only the CZE samples are included by default, unless directories are excluded instead
+    let default_subdirs = [OsString::from("CZE")];
+    let sample_subdirs = match options.sample_subdirs.is_empty() && options.exclude.is_empty() {
+        true => &default_subdirs[..],
+        false => options.sample_subdirs,
+    };
+    let dir = &unwrap_single_directory(dir, sample_subdirs, options.exclude);
+
     let mut samples: HashMap<String, SampleFiles> = HashMap::new();
-    let mut depth = 0;
-    visit_files(dir, |event| {
-        match event {
-            TraversalEvent::EnterDirectory(dir) => {
-                if depth == 0
-                    && !sample_subdirs.is_empty()
-                    && !sample_subdirs
-                        .iter()
-                        .find(|s| dir.file_name().unwrap() == s.as_os_str())
-                        .is_some()
-                {
-                    return TraversalResponse::Skip;
+    visit_files(
+        dir,
+        Some(MAX_SAMPLE_DEPTH),
+        options.follow_symlinks,
+        |event| {
+            match event {
+                TraversalEvent::EnterDirectory(dir, 0) => {
+                    let name = dir.file_name().unwrap();
+                    // an excluded directory is skipped even if it is also included
+                    if options.exclude.iter().any(|s| name == s.as_os_str())
+                        || !sample_subdirs.is_empty()
+                            && !sample_subdirs.iter().any(|s| name == s.as_os_str())
+                    {
+                        log::trace!("skipping directory `{}`", name.to_string_lossy());
+                        return TraversalResponse::Skip;
+                    }
                 }
-                depth += 1;
-            }
-            TraversalEvent::LeaveDirectory => {
-                depth -= 1;
-            }
-            TraversalEvent::File(file) => {
-                let relative = file.strip_prefix(dir).unwrap();
-                let mut add = |name: &str, os: Os, input: bool| {
-                    log::trace!(
-                        "Found sample file {}: endings {os:?}, input {input}",
-                        relative.display()
-                    );
-                    if target_os == os {
-                        let raw_key = relative.parent().unwrap().join(name);
-                        let key = raw_key.to_str().unwrap().to_owned().replace('/', "_");
-
-                        let entry = samples.entry(key).or_default();
-                        if input {
-                            if entry.input.is_some() {
-                                log::error!("duplicate input file {}", relative.display());
+                TraversalEvent::EnterDirectory(..) => {}
+                TraversalEvent::LeaveDirectory => {}
+                TraversalEvent::File(file) => {
+                    let relative = file.strip_prefix(dir).unwrap();
+                    let mut add = |name: &str, os: Os, input: bool| {
+                        log::trace!(
+                            "Found sample file {}: endings {os:?}, input {input}",
+                            fs::display(relative)
+                        );
+                        if target_os == os {
+                            let raw_key = relative.parent().unwrap().join(name);
+                            let key = raw_key.to_str().unwrap().to_owned().replace('/', "_");
+
+                            let entry = samples.entry(key).or_default();
+                            if input {
+                                if entry.input.is_some() {
+                                    log::error!("duplicate input file {}", fs::display(relative));
+                                }
+                                entry.input = Some(file.to_owned());
+                            } else {
+                                if entry.output.is_some() {
+                                    log::error!("duplicate output file {}", fs::display(relative));
+                                }
+                                entry.output = Some(file.to_owned());
                             }
-                            entry.input = Some(file.to_owned());
                         } else {
-                            if entry.output.is_some() {
-                                log::error!("duplicate output file {}", relative.display());
-                            }
-                            entry.output = Some(file.to_owned());
+                            log::trace!(
+                                "skipping {}: line endings do not match",
+                                fs::display(relative)
+                            );
                         }
-                    } else {
-                        log::trace!("skipping {}: line endings do not match", relative.display());
+                    };
+
+                    let name = file.file_name().unwrap().to_str().unwrap();
+                    if let Some(name) = name.strip_suffix("_in.txt") {
+                        add(name, Os::Unix, true);
+                    } else if let Some(name) = name.strip_suffix("_out.txt") {
+                        add(name, Os::Unix, false);
+                    } else if let Some(name) = name.strip_suffix("_in_win.txt") {
+                        add(name, Os::Windows, true);
+                    } else if let Some(name) = name.strip_suffix("_out_win.txt") {
+                        add(name, Os::Windows, false);
                     }
-                };
+                }
+            }
+            TraversalResponse::Continue
+        },
+    );
 
-                let name = file.file_name().unwrap().to_str().unwrap();
-                if let Some(name) = name.strip_suffix("_in.txt") {
-                    add(name, Os::Unix, true);
-                } else if let Some(name) = name.strip_suffix("_out.txt") {
-                    add(name, Os::Unix, false);
-                } else if let Some(name) = name.strip_suffix("_in_win.txt") {
-                    add(name, Os::Windows, true);
-                } else if let Some(name) = name.strip_suffix("_out_win.txt") {
-                    add(name, Os::Windows, false);
+    let mut unmatched = false;
+    let mut samples = samples
+        .drain()
+        .filter_map(|(key, files)| match (files.input, files.output) {
+            (Some(input), Some(output)) => Some((key, input, output)),
+            (input, _) => {
+                let missing = match input {
+                    Some(_) => "output",
+                    None => "input",
+                };
+                match options.strict {
+                    true => log::error!("sample `{key}` has no matching {missing} file"),
+                    false => log::warn!("sample `{key}` has no matching {missing} file, skipping"),
                 }
+                unmatched = true;
+                None
             }
+        })
+        .collect::<Vec<_>>();
+
+    if options.strict && unmatched {
+        return Err(AlreadyReported);
+    }
+
+    samples.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+    Ok(samples)
+}
+
+/// Descends through directories which are the only entry of their parent and only contain
+/// other directories, archives are often wrapped in one with the sample directories inside
+///
+/// Included and excluded directories are never descended into, they are the sample directories
+fn unwrap_single_directory(
+    dir: &Path,
+    sample_subdirs: &[OsString],
+    exclude: &[OsString],
+) -> PathBuf {
+    let only_directories = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .is_ok_and(|mut entries| entries.all(|e| e.is_ok_and(|e| e.path().is_dir())))
+    };
+    let mut dir = dir.to_owned();
+    loop {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return dir;
+        };
+        let entries = entries.flatten().take(2).collect::<Vec<_>>();
+        let [entry] = &entries[..] else {
+            return dir;
+        };
+        let name = entry.file_name();
+        if sample_subdirs.contains(&name)
+            || exclude.contains(&name)
+            || !only_directories(&entry.path())
+        {
+            return dir;
         }
-        TraversalResponse::Continue
-    });
+        log::info!(
+            "descending into `{}`, the only entry of its parent directory",
+            name.to_string_lossy()
+        );
+        dir.push(name);
+    }
+}
 
-    let mut samples = samples.drain().collect::<Vec<_>>();
-    samples.sort_by(|(a, _), (b, _)| a.cmp(b));
-    samples
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    name: &'a str,
+    input: &'a Path,
+    output: &'a Path,
 }
 
-fn make_samples_string(collected: &[(String, SampleFiles)]) -> fs::Result<Vec<u8>> {
+/// Writes the collected samples with the paths of their files inside the archive
+fn write_manifest(
+    path: &Path,
+    collected: &[(String, PathBuf, PathBuf)],
+    extract_dir: &Path,
+) -> fs::Result<()> {
+    let entries = collected
+        .iter()
+        .map(|(name, input, output)| ManifestEntry {
+            name,
+            input: input.strip_prefix(extract_dir).unwrap_or(input),
+            output: output.strip_prefix(extract_dir).unwrap_or(output),
+        })
+        .collect::<Vec<_>>();
+    let serialized = serde_json::to_string_pretty(&entries).unwrap();
+
+    log::debug!("writing manifest to `{}`", fs::display(path));
+    fs::write(path, serialized.as_bytes())
+}
+
+/// Pairs up the sample files in a directory like `convert` with its default options and
+/// returns the contents of the samples file it would write
+pub fn samples_from_dir(dir: &Path, target_os: Os, separator: &str) -> fs::Result<Vec<u8>> {
+    if !dir.is_dir() {
+        bail!("the sample directory `{}` doesn't exist", fs::display(dir));
+    }
+    let collected = collect_sample_files(dir, target_os, &SampleSelection::default())?;
+    if collected.is_empty() {
+        bail!("`{}` contains no sample files", fs::display(dir));
+    }
+    log::debug!(
+        "found {} samples in `{}`",
+        collected.len(),
+        fs::display(dir)
+    );
+    make_samples_string(&collected, separator, None)
+}
+
+fn make_samples_string(
+    collected: &[(String, PathBuf, PathBuf)],
+    separator: &str,
+    compress_large: Option<usize>,
+) -> fs::Result<Vec<u8>> {
     let mut buf = Vec::new();
-    for (file, sample) in collected {
-        let input = sample.input.as_ref().unwrap();
-        let output = sample.output.as_ref().unwrap();
-
-        _ = writeln!(buf, "---");
-        _ = writeln!(buf, "{file} in");
-        _ = writeln!(buf, "---");
-        fs::read_into(input, &mut buf)?;
-        if !buf.ends_with(b"\n") {
-            buf.push(b'\n');
-        }
+    for (file, input, output) in collected {
+        for (path, kind) in [(input, "in"), (output, "out")] {
+            let mut body = Vec::new();
+            fs::read_into(path, &mut body)?;
+            // only inputs can be compressed, outputs are compared and updated as text
+            let compress = kind == "in" && compress_large.is_some_and(|n| body.len() > n);
 
-        _ = writeln!(buf, "---");
-        _ = writeln!(buf, "{file} out");
-        _ = writeln!(buf, "---");
-        fs::read_into(&output, &mut buf)?;
-        if !buf.ends_with(b"\n") {
-            buf.push(b'\n');
+            _ = writeln!(buf, "{separator}");
+            match compress {
+                true => _ = writeln!(buf, "{file} in gz"),
+                false => _ = writeln!(buf, "{file} {kind}"),
+            }
+            _ = writeln!(buf, "{separator}");
+
+            let start = buf.len();
+            match compress {
+                true => buf.extend_from_slice(&compress_input(&body)),
+                false => buf.extend_from_slice(&body),
+            }
+            if buf[start..]
+                .lines()
+                .any(|line| line == separator.as_bytes())
+            {
+                log::warn!(
+                    "`{}` contains the separator `{separator}`, pick a different one with --separator",
+                    fs::display(path)
+                );
+            }
+            if !buf.ends_with(b"\n") {
+                buf.push(b'\n');
+            }
         }
     }
 
     Ok(buf)
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveFormat {
+    TarGz,
+    TarXz,
+    TarBz2,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // longer extensions go first so that `.tar.gz` is stripped whole
+    const EXTENSIONS: &'static [(&'static str, ArchiveFormat)] = &[
+        (".tar.gz", ArchiveFormat::TarGz),
+        (".tgz", ArchiveFormat::TarGz),
+        (".gz", ArchiveFormat::TarGz),
+        (".tar.xz", ArchiveFormat::TarXz),
+        (".txz", ArchiveFormat::TarXz),
+        (".xz", ArchiveFormat::TarXz),
+        (".tar.bz2", ArchiveFormat::TarBz2),
+        (".tbz2", ArchiveFormat::TarBz2),
+        (".tbz", ArchiveFormat::TarBz2),
+        (".bz2", ArchiveFormat::TarBz2),
+        (".zip", ArchiveFormat::Zip),
+    ];
+
+    /// Detects the format from the file name, returns it along with the name stripped of the extension
+    pub fn from_file_name(name: &str) -> Option<(ArchiveFormat, &str)> {
+        Self::EXTENSIONS
+            .iter()
+            .find_map(|(ext, format)| name.strip_suffix(ext).map(|stem| (*format, stem)))
+    }
+}
+
 fn extract_archive(archive: &Path, extract_dir: &Path) -> fs::Result<()> {
-    let mut builder = std::process::Command::new("tar");
-    builder.arg("-xzf").arg(archive).arg("-C").arg(extract_dir);
+    let name = archive
+        .file_name()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or("");
+    let format = match ArchiveFormat::from_file_name(name) {
+        Some((format, _)) => format,
+        None => {
+            log::debug!("unknown archive extension, assuming a gzipped tarball");
+            ArchiveFormat::TarGz
+        }
+    };
 
-    print_args(&builder);
-    check_status("tar", builder.status())
+    match format {
+        ArchiveFormat::TarGz => {
+            let file = fs::open(archive)?;
+            unpack_tar(flate2::read::GzDecoder::new(file), archive, extract_dir)
+        }
+        ArchiveFormat::TarXz => {
+            let file = fs::open(archive)?;
+            unpack_tar(xz2::read::XzDecoder::new(file), archive, extract_dir)
+        }
+        ArchiveFormat::TarBz2 => {
+            let file = fs::open(archive)?;
+            unpack_tar(bzip2::read::BzDecoder::new(file), archive, extract_dir)
+        }
+        ArchiveFormat::Zip => {
+            let mut builder = std::process::Command::new("unzip");
+            builder.arg("-q").arg(archive).arg("-d").arg(extract_dir);
+
+            print_args(&builder);
+            check_status("unzip", builder.status())
+        }
+    }
+}
+
+fn unpack_tar(decoder: impl std::io::Read, archive: &Path, extract_dir: &Path) -> fs::Result<()> {
+    tar::Archive::new(decoder).unpack(extract_dir).map_err(|e| {
+        let message = format!("failed to extract `{}`", fs::display(archive));
+        fs::report_custom(message, e)
+    })
 }
 
 pub fn subcommand_convert(
     out_dir: &Path,
-    archive: &Path,
     converted_file: &Path,
     args: &Arguments,
-    sample_subdirs: &[OsString],
+    options: &ConvertOpts,
 ) -> fs::Result<()> {
     let extract_dir = out_dir.join("extract");
     if extract_dir.exists() {
         _ = fs::remove_dir_all(&extract_dir);
     }
     fs::create_dir_all(&extract_dir)?;
-    extract_archive(archive, &extract_dir)?;
 
-    let collected = collect_sample_files(&extract_dir, args.os, sample_subdirs);
+    // after a failure the extracted files are left behind to find out what went wrong
+    convert_extracted(&extract_dir, converted_file, args, options)?;
+    if !options.keep_extracted {
+        fs::remove_dir_all(&extract_dir)?;
+    }
+    Ok(())
+}
+
+fn convert_extracted(
+    extract_dir: &Path,
+    converted_file: &Path,
+    args: &Arguments,
+    options: &ConvertOpts,
+) -> fs::Result<()> {
+    extract_archive(&options.archive, extract_dir)?;
+
+    let collected = collect_sample_files(extract_dir, args.os, &SampleSelection::new(options))?;
     if collected.is_empty() {
+        if options.require_samples {
+            bail!("archive contains no sample files");
+        }
         log::info!("archive contains no sample files");
         return Ok(());
     }
 
-    let contents = make_samples_string(&collected)?;
+    let contents = make_samples_string(&collected, &options.separator, options.compress_large)?;
+    if let Some(manifest) = &options.manifest {
+        write_manifest(manifest, &collected, extract_dir)?;
+    }
 
-    log::trace!("writing converted file to `{}`", converted_file.display());
+    log::trace!(
+        "writing converted file to `{}`",
+        fs::display(converted_file)
+    );
     fs::write(converted_file, &contents)
 }
@@ -0,0 +1,68 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{bail, fs, fs::AlreadyReported, interrupt};
+
+// editors tend to produce several events for a single save
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Blocks until one of the files is created, modified or removed
+pub fn wait_for_change(files: &[PathBuf]) -> fs::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)
+        .map_err(|e| fs::report_custom("failed to create a file watcher", e))?;
+
+    // the parent directories are watched because editors often replace files instead of writing them
+    let mut watched = HashSet::new();
+    let mut directories = HashSet::new();
+    for file in files {
+        let parent = match file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let Ok(parent) = std::fs::canonicalize(parent) else {
+            log::debug!(
+                "cannot watch `{}`, its directory doesn't exist",
+                fs::display(file)
+            );
+            continue;
+        };
+        watched.insert(parent.join(file.file_name().unwrap_or_default()));
+        directories.insert(parent);
+    }
+    for directory in &directories {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(|e| {
+                fs::report_custom(format!("failed to watch `{}`", fs::display(directory)), e)
+            })?;
+    }
+
+    log::info!("Watching {} files for changes", watched.len());
+    loop {
+        // woken up regularly to notice Ctrl-C
+        match receiver.recv_timeout(DEBOUNCE) {
+            Ok(Ok(event)) => {
+                let relevant = !matches!(event.kind, EventKind::Access(_))
+                    && event.paths.iter().any(|path| watched.contains(path));
+                if relevant {
+                    log::debug!("{:?} {:?}", event.kind, event.paths);
+                    break;
+                }
+            }
+            Ok(Err(e)) => log::debug!("file watcher error: {e}"),
+            Err(mpsc::RecvTimeoutError::Timeout) if interrupt::interrupted() => return Ok(()),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("file watcher disconnected"),
+        }
+    }
+    while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+
+    Ok(())
+}